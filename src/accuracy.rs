@@ -0,0 +1,100 @@
+//! A direct-sum reference solver, and a comparison against it. Use these to choose θ, `max_bodies_per_node`,
+//! and the other `BhConfig` parameters for a required accuracy.
+
+use rayon::prelude::*;
+
+use crate::{BhConfig, BodyModel, Float, Tree, Vector, force_from_src};
+
+#[derive(Clone, Debug, Default)]
+/// Per-body relative force errors of the Barnes Hut approximation, compared to a direct sum.
+/// Errors are `|f_bh - f_direct| / |f_direct|`.
+pub struct AccuracyReport<F: Float = f64> {
+    /// Root-mean-square relative error.
+    pub rms: F,
+    /// 99th percentile relative error.
+    pub p99: F,
+    /// The largest relative error.
+    pub max: F,
+    /// The number of bodies compared. Bodies with zero direct force are skipped.
+    pub num_bodies: usize,
+}
+
+/// Calculate force by summing over all source bodies, with no grouping. This is O(N) per target, and O(N^2)
+/// for all targets. It uses the same force function as `run_bh`. `id_target` is the index in `bodies`
+/// of the target; it prevents self-interaction. Use `usize::MAX` if the target isn't a source.
+pub fn run_direct<F, T, K>(
+    posit_target: F::Vec3,
+    id_target: usize,
+    bodies: &[T],
+    force_fn: &K,
+) -> F::Vec3
+where
+    F: Float,
+    T: BodyModel<F> + Sync,
+    K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+{
+    bodies
+        .par_iter()
+        .enumerate()
+        .filter(|(i, _)| *i != id_target)
+        .map(|(_, body)| force_from_src(body.posit() - posit_target, body.mass(), force_fn))
+        .reduce(F::Vec3::new_zero, |acc, elem| acc + elem)
+}
+
+impl<F: Float> Tree<F> {
+    /// Compare `run_bh` to a direct sum, using the bodies the tree was built from as both sources and
+    /// targets. This is O(N^2). Distances in the direct sum use the minimum image if `config.periodic`
+    /// is set. For `OpeningCriterion::RelativeForce`, each target's direct-sum acceleration is used as its
+    /// previous acceleration.
+    pub fn accuracy_report<K>(&self, config: &BhConfig<F>, force_fn: &K) -> AccuracyReport<F>
+    where
+        K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+    {
+        let coupling = self.coupling_estimate(force_fn);
+
+        let mut errors: Vec<F> = self
+            .bodies
+            .par_iter()
+            .zip(&self.body_ids)
+            .filter_map(|(target, &id_target)| {
+                let posit_target = target.posit;
+
+                let mut direct = F::Vec3::new_zero();
+                for (body, &id) in self.bodies.iter().zip(&self.body_ids) {
+                    if id == id_target {
+                        continue;
+                    }
+                    let acc_diff = self.displacement(body.posit, posit_target, config);
+                    direct += force_from_src(acc_diff, body.mass, force_fn);
+                }
+
+                let direct_mag = direct.magnitude();
+                if direct_mag == F::zero() {
+                    return None;
+                }
+
+                let acc_prev = coupling.map(|c| direct_mag / c);
+                let bh = self.force_serial(posit_target, id_target, acc_prev, config, force_fn);
+
+                Some((bh - direct).magnitude() / direct_mag)
+            })
+            .collect();
+
+        if errors.is_empty() {
+            return Default::default();
+        }
+
+        errors.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let n = errors.len();
+        let sum_sq: F = errors.iter().map(|e| *e * *e).sum();
+        let i_p99 = (n * 99).div_ceil(100).saturating_sub(1);
+
+        AccuracyReport {
+            rms: (sum_sq / F::from_f64(n as f64)).sqrt(),
+            p99: errors[i_p99],
+            max: errors[n - 1],
+            num_bodies: n,
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Checkpoints for `Simulation`, allowing long runs to stop, and resume. A checkpoint holds body state,
+//! time step bookkeeping, the `BhConfig`, and the current tree, including its root `Cube`. Resuming from one
+//! is bit-identical to continuing the original run.
+//!
+//! Format: An 8-byte magic number, a little-endian `u32` format version, a `u8` float width in bytes,
+//! a little-endian `u64` payload length, and a little-endian `u32` CRC-32 of the payload, followed by the
+//! payload, encoded with bincode's standard configuration. Floats are stored as their raw bits.
+
+use std::{fmt, io, io::Read, io::Write, mem::size_of};
+
+use bincode::{
+    Decode, Encode, config,
+    error::{DecodeError, EncodeError},
+};
+
+use crate::{BhConfig, Float, Simulation, Tree};
+
+const MAGIC: &[u8; 8] = b"BHCHKPT\0";
+
+/// Increment this when the payload layout changes.
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// CRC-32 (IEEE 802.3), with the reflected polynomial.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Encode(EncodeError),
+    Decode(DecodeError),
+    /// The data doesn't start with the checkpoint magic number; it's likely not a checkpoint.
+    BadMagic,
+    /// The checkpoint was written by an incompatible version of this library.
+    UnsupportedVersion(u32),
+    /// The checkpoint was written with a different float type; e.g. `f32` instead of `f64`.
+    FloatWidth {
+        expected: u8,
+        found: u8,
+    },
+    /// The payload doesn't match its checksum; the file is corrupted, or truncated.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Checkpoint IO error: {e}"),
+            Self::Encode(e) => write!(f, "Checkpoint encode error: {e}"),
+            Self::Decode(e) => write!(f, "Checkpoint decode error: {e}"),
+            Self::BadMagic => write!(f, "Not a checkpoint: bad magic number"),
+            Self::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported checkpoint version {v}; expected {CHECKPOINT_VERSION}"
+            ),
+            Self::FloatWidth { expected, found } => write!(
+                f,
+                "Checkpoint float width is {found} bytes; expected {expected}"
+            ),
+            Self::ChecksumMismatch => write!(f, "Checkpoint checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Encode(e) => Some(e),
+            Self::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Everything in `Simulation` except the integrator, and force function.
+type Payload<T, F> = (
+    Vec<T>,
+    BhConfig<F>,
+    F,                       // dt
+    F,                       // bb_pad
+    F,                       // time
+    Vec<<F as Float>::Vec3>, // accel
+    Vec<u32>,                // levels
+    Option<Tree<F>>,         // tree
+    usize,                   // rebuilds
+);
+
+impl<T, I, K, F> Simulation<T, I, K, F>
+where
+    F: Float,
+    T: Encode + Decode<()>,
+{
+    /// Write a checkpoint of the simulation's state. The integrator and force function aren't included;
+    /// pass them to `from_checkpoint`. Nor are `BlockTimesteps`; each body's current level is.
+    pub fn write_checkpoint<W: Write>(&self, mut writer: W) -> Result<(), CheckpointError> {
+        let payload = bincode::encode_to_vec(
+            (
+                &self.bodies,
+                &self.config,
+                self.dt,
+                self.bb_pad,
+                self.time,
+                &self.accel,
+                &self.levels,
+                &self.tree,
+                self.rebuilds,
+            ),
+            config::standard(),
+        )
+        .map_err(CheckpointError::Encode)?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        writer.write_all(&[size_of::<F>() as u8])?;
+        writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+        writer.write_all(&crc32(&payload).to_le_bytes())?;
+        writer.write_all(&payload)?;
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Restore a simulation from a checkpoint written by `write_checkpoint`. Stepping the result is
+    /// bit-identical to stepping the original, given the same integrator, and force function.
+    pub fn from_checkpoint<R: Read>(
+        mut reader: R,
+        integrator: I,
+        force_fn: K,
+    ) -> Result<Self, CheckpointError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CheckpointError::BadMagic);
+        }
+
+        let mut buf_u32 = [0; 4];
+        reader.read_exact(&mut buf_u32)?;
+        let version = u32::from_le_bytes(buf_u32);
+        if version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+
+        let mut float_width = [0; 1];
+        reader.read_exact(&mut float_width)?;
+        let expected = size_of::<F>() as u8;
+        if float_width[0] != expected {
+            return Err(CheckpointError::FloatWidth {
+                expected,
+                found: float_width[0],
+            });
+        }
+
+        let mut buf_u64 = [0; 8];
+        reader.read_exact(&mut buf_u64)?;
+        let len = u64::from_le_bytes(buf_u64);
+
+        reader.read_exact(&mut buf_u32)?;
+        let checksum = u32::from_le_bytes(buf_u32);
+
+        // Read via `take`, so a corrupted length can't trigger a huge allocation up front.
+        let mut payload = Vec::new();
+        reader.take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len || crc32(&payload) != checksum {
+            return Err(CheckpointError::ChecksumMismatch);
+        }
+
+        let ((bodies, config, dt, bb_pad, time, accel, levels, tree, rebuilds), _): (
+            Payload<T, F>,
+            _,
+        ) = bincode::decode_from_slice(&payload, config::standard())
+            .map_err(CheckpointError::Decode)?;
+
+        Ok(Self {
+            bodies,
+            config,
+            integrator,
+            force_fn,
+            dt,
+            bb_pad,
+            time,
+            accel,
+            levels,
+            tree,
+            rebuilds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lin_alg::f64::Vec3;
+
+    use super::*;
+    use crate::{BlockTimesteps, Leapfrog, kernels, test_utils};
+
+    /// Header offsets.
+    const VERSION_I: usize = 8;
+    const FLOAT_WIDTH_I: usize = 12;
+
+    type Sim = Simulation<test_utils::Body, Leapfrog, fn(Vec3, f64, f64) -> Vec3>;
+
+    fn force(acc_dir: Vec3, mass_src: f64, dist: f64) -> Vec3 {
+        kernels::plummer(0.05)(acc_dir, mass_src, dist)
+    }
+
+    fn sim() -> Sim {
+        let config = BhConfig {
+            periodic: false,
+            ..test_utils::config()
+        };
+        Simulation::new(test_utils::bodies(60), config, Leapfrog, force, 0.01, 0.2)
+    }
+
+    fn checkpoint_bytes(sim: &Sim) -> Vec<u8> {
+        let mut result = Vec::new();
+        sim.write_checkpoint(&mut result).unwrap();
+        result
+    }
+
+    fn assert_same_state(a: &Sim, b: &Sim) {
+        assert_eq!(a.bodies.len(), b.bodies.len());
+        for (x, y) in a.bodies.iter().zip(&b.bodies) {
+            assert_eq!(test_utils::bits(x.posit), test_utils::bits(y.posit));
+            assert_eq!(test_utils::bits(x.velocity), test_utils::bits(y.velocity));
+            assert_eq!(x.mass.to_bits(), y.mass.to_bits());
+        }
+
+        let accel_bits = |s: &Sim| {
+            s.accel
+                .iter()
+                .map(|a| test_utils::bits(*a))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(accel_bits(a), accel_bits(b));
+
+        assert_eq!(a.levels, b.levels);
+        assert_eq!(a.time.to_bits(), b.time.to_bits());
+        assert_eq!(a.rebuilds, b.rebuilds);
+        test_utils::assert_trees_eq(a.tree.as_ref().unwrap(), b.tree.as_ref().unwrap());
+    }
+
+    fn restore_err(bytes: &[u8]) -> CheckpointError {
+        match Sim::from_checkpoint(bytes, Leapfrog, force) {
+            Ok(_) => panic!("Expected the checkpoint to be rejected"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn restart_is_bit_identical() {
+        let mut original = sim();
+        original.run(5);
+
+        let bytes = checkpoint_bytes(&original);
+        let mut restored = Sim::from_checkpoint(bytes.as_slice(), Leapfrog, force).unwrap();
+        assert_same_state(&original, &restored);
+
+        original.run(10);
+        restored.run(10);
+        assert_same_state(&original, &restored);
+    }
+
+    #[test]
+    fn restart_block_is_bit_identical() {
+        let timesteps = BlockTimesteps {
+            max_level: 3,
+            η: 0.025,
+            softening: 0.05,
+        };
+
+        let mut original = sim();
+        original.step_block(&timesteps);
+        original.step_block(&timesteps);
+
+        let bytes = checkpoint_bytes(&original);
+        let mut restored = Sim::from_checkpoint(bytes.as_slice(), Leapfrog, force).unwrap();
+        assert_same_state(&original, &restored);
+
+        for _ in 0..5 {
+            original.step_block(&timesteps);
+            restored.step_block(&timesteps);
+        }
+        assert_same_state(&original, &restored);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = checkpoint_bytes(&sim());
+        bytes[0] ^= 0xff;
+        assert!(matches!(restore_err(&bytes), CheckpointError::BadMagic));
+    }
+
+    #[test]
+    fn rejects_wrong_version() {
+        let mut bytes = checkpoint_bytes(&sim());
+        bytes[VERSION_I..VERSION_I + 4].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            restore_err(&bytes),
+            CheckpointError::UnsupportedVersion(v) if v == CHECKPOINT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_float_width() {
+        let mut bytes = checkpoint_bytes(&sim());
+        bytes[FLOAT_WIDTH_I] = 4;
+        assert!(matches!(
+            restore_err(&bytes),
+            CheckpointError::FloatWidth {
+                expected: 8,
+                found: 4
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        let mut bytes = checkpoint_bytes(&sim());
+        let i = bytes.len() - 10;
+        bytes[i] ^= 0x01;
+        assert!(matches!(
+            restore_err(&bytes),
+            CheckpointError::ChecksumMismatch
+        ));
+
+        let bytes = checkpoint_bytes(&sim());
+        assert!(matches!(
+            restore_err(&bytes[..bytes.len() - 1]),
+            CheckpointError::ChecksumMismatch
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+//! Bincode support for types that are generic over `Float`. We implement these manually, since
+//! the derive macros don't support default type parameters.
+
+use bincode::{
+    BorrowDecode, Decode, Encode,
+    de::{BorrowDecoder, Decoder},
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+};
+
+use crate::{BhConfig, Cube, Float, Node, SourceBody, Tree};
+
+macro_rules! impl_encode {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        impl<F: Float> Encode for $ty<F> {
+            fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+                $(self.$field.encode(encoder)?;)*
+                Ok(())
+            }
+        }
+
+        impl<F: Float> Decode<()> for $ty<F> {
+            fn decode<D: Decoder<Context = ()>>(decoder: &mut D) -> Result<Self, DecodeError> {
+                Ok(Self {
+                    $($field: Decode::decode(decoder)?,)*
+                })
+            }
+        }
+
+        impl<'de, F: Float> BorrowDecode<'de, ()> for $ty<F> {
+            fn borrow_decode<D: BorrowDecoder<'de, Context = ()>>(
+                decoder: &mut D,
+            ) -> Result<Self, DecodeError> {
+                Decode::decode(decoder)
+            }
+        }
+    };
+}
+
+impl_encode!(BhConfig {
+    θ,
+    max_bodies_per_node,
+    max_tree_depth,
+    expansion_order,
+    periodic,
+    opening_criterion,
+    θ_fallback,
+});
+
+impl_encode!(Cube { center, width });
+
+impl_encode!(Node {
+    id,
+    bounding_box,
+    children,
+    mass,
+    center_of_mass,
+    mass_pos,
+    center_pos,
+    mass_neg,
+    center_neg,
+    dipole,
+    quadrupole,
+    octupole,
+    body_range,
+});
+
+impl_encode!(SourceBody { posit, mass });
+
+impl_encode!(Tree {
+    nodes,
+    bodies,
+    body_ids,
+    body_indices,
+    depth_limited,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn round_trip<T: Encode + Decode<()>>(val: &T) -> T {
+        let cfg = bincode::config::standard();
+        let bytes = bincode::encode_to_vec(val, cfg).unwrap();
+        let (result, len) = bincode::decode_from_slice(&bytes, cfg).unwrap();
+        assert_eq!(len, bytes.len());
+        result
+    }
+
+    #[test]
+    fn tree_round_trip() {
+        let tree = test_utils::tree();
+        test_utils::assert_trees_eq(&round_trip(&tree), &tree);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = test_utils::config();
+        test_utils::assert_configs_eq(&round_trip(&config), &config);
+    }
+}
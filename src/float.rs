@@ -0,0 +1,128 @@
+//! Allows the tree and its evaluation to be generic over `f32` and `f64`, using the `Vec3` type
+//! from `lin_alg` for each.
+
+use std::{
+    fmt::{Debug, Display},
+    iter::Sum,
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign},
+};
+
+#[cfg(feature = "encode")]
+use bincode::{BorrowDecode, Decode, Encode};
+
+/// A floating point type the tree can be built with: `f32` or `f64`.
+pub trait Float:
+    num_traits::Float
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + Sum
+    + Default
+    + Debug
+    + Display
+    + Send
+    + Sync
+    + Encodable
+    + 'static
+{
+    /// The `lin_alg` vector type for this precision.
+    type Vec3: Vector<Self> + Encodable;
+
+    /// For converting constants. Lossy for `f32`.
+    fn from_f64(v: f64) -> Self;
+}
+
+/// With the `encode` feature, requires bincode support, so types built from `Float` can implement it.
+#[cfg(feature = "encode")]
+pub trait Encodable: Encode + Decode<()> + for<'de> BorrowDecode<'de, ()> {}
+
+#[cfg(feature = "encode")]
+impl<T: Encode + Decode<()> + for<'de> BorrowDecode<'de, ()>> Encodable for T {}
+
+/// With the `encode` feature, requires bincode support, so types built from `Float` can implement it.
+#[cfg(not(feature = "encode"))]
+pub trait Encodable {}
+
+#[cfg(not(feature = "encode"))]
+impl<T> Encodable for T {}
+
+/// Operations we use on `lin_alg`'s `Vec3` types. Use `x()` etc in place of field access
+/// in generic code.
+pub trait Vector<F>:
+    Copy
+    + Debug
+    + Send
+    + Sync
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<F, Output = Self>
+    + Div<F, Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign<F>
+    + DivAssign<F>
+{
+    fn new(x: F, y: F, z: F) -> Self;
+    fn new_zero() -> Self;
+    fn x(&self) -> F;
+    fn y(&self) -> F;
+    fn z(&self) -> F;
+    fn dot(&self, other: Self) -> F;
+    fn magnitude_squared(&self) -> F;
+    fn magnitude(&self) -> F;
+}
+
+macro_rules! impl_float {
+    ($f:ident) => {
+        impl Float for $f {
+            type Vec3 = lin_alg::$f::Vec3;
+
+            fn from_f64(v: f64) -> Self {
+                v as $f
+            }
+        }
+
+        impl Vector<$f> for lin_alg::$f::Vec3 {
+            fn new(x: $f, y: $f, z: $f) -> Self {
+                Self { x, y, z }
+            }
+
+            fn new_zero() -> Self {
+                Self {
+                    x: 0.,
+                    y: 0.,
+                    z: 0.,
+                }
+            }
+
+            fn x(&self) -> $f {
+                self.x
+            }
+
+            fn y(&self) -> $f {
+                self.y
+            }
+
+            fn z(&self) -> $f {
+                self.z
+            }
+
+            fn dot(&self, other: Self) -> $f {
+                self.x * other.x + self.y * other.y + self.z * other.z
+            }
+
+            fn magnitude_squared(&self) -> $f {
+                self.x.powi(2) + self.y.powi(2) + self.z.powi(2)
+            }
+
+            fn magnitude(&self) -> $f {
+                (self.x.powi(2) + self.y.powi(2) + self.z.powi(2)).sqrt()
+            }
+        }
+    };
+}
+
+impl_float!(f32);
+impl_float!(f64);
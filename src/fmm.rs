@@ -0,0 +1,295 @@
+//! A Fast Multipole Method (FMM) evaluation, built on the same octree as `run_bh`. It groups target bodies
+//! in addition to source ones, leading to O(N) computation time for all pairwise forces. It's specific
+//! to 1/r potentials, e.g. gravity, or Coulomb force.
+//!
+//! We use a dual-tree traversal: Pairs of well-separated (target, source) nodes interact via a
+//! multipole-to-local (M2L) translation, building a Taylor expansion of the field about the target
+//! node's center. Other pairs are split, or, for leaf pairs, summed directly. We then translate each
+//! local expansion down the tree (L2L), and evaluate it at each body.
+//!
+//! Sources use their monopole, dipole, and quadrupole moments; build the tree with `ExpansionOrder::Quadrupole`
+//! or higher for best accuracy. (The dipole about the center of mass is zero unless a node has mixed signs.)
+//! Local expansions include field, gradient, and (monopole-only) second derivative terms.
+
+use crate::{BhConfig, Float, Node, Tree, Vector};
+
+type Tensor1<F> = [F; 3];
+type Tensor2<F> = [[F; 3]; 3];
+type Tensor3<F> = [[[F; 3]; 3]; 3];
+
+#[derive(Clone, Copy)]
+/// A Taylor expansion of the field (the gradient of the potential) about a node's center:
+/// `field(center + y) = c1 + c2·y + c3:yy / 2`.
+struct Local<F: Float> {
+    c1: Tensor1<F>,
+    c2: Tensor2<F>,
+    c3: Tensor3<F>,
+}
+
+impl<F: Float> Local<F> {
+    fn new_zero() -> Self {
+        Self {
+            c1: [F::zero(); 3],
+            c2: [[F::zero(); 3]; 3],
+            c3: [[[F::zero(); 3]; 3]; 3],
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        for i in 0..3 {
+            self.c1[i] += other.c1[i];
+            for j in 0..3 {
+                self.c2[i][j] += other.c2[i][j];
+                for k in 0..3 {
+                    self.c3[i][j][k] += other.c3[i][j][k];
+                }
+            }
+        }
+    }
+
+    /// L2L: Re-center this expansion at `center + s`.
+    fn shifted(&self, s: Tensor1<F>) -> Self {
+        let mut result = *self;
+        let half = F::from_f64(0.5);
+
+        for i in 0..3 {
+            for j in 0..3 {
+                result.c1[i] += self.c2[i][j] * s[j];
+                for k in 0..3 {
+                    result.c1[i] += half * self.c3[i][j][k] * s[j] * s[k];
+                    result.c2[i][j] += self.c3[i][j][k] * s[k];
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Evaluate the field at `center + y`.
+    fn eval(&self, y: Tensor1<F>) -> F::Vec3 {
+        let half = F::from_f64(0.5);
+        let mut result = self.c1;
+
+        for (i, r) in result.iter_mut().enumerate() {
+            for j in 0..3 {
+                *r += self.c2[i][j] * y[j];
+                for k in 0..3 {
+                    *r += half * self.c3[i][j][k] * y[j] * y[k];
+                }
+            }
+        }
+
+        F::Vec3::new(result[0], result[1], result[2])
+    }
+}
+
+fn to_arr<F: Float>(v: F::Vec3) -> Tensor1<F> {
+    [v.x(), v.y(), v.z()]
+}
+
+fn delta<F: Float>(a: usize, b: usize) -> F {
+    if a == b { F::one() } else { F::zero() }
+}
+
+/// M2L: The local expansion, about a target center, of the field from a source node's monopole,
+/// dipole, and quadrupole. `r` is the target center minus the source's center of mass.
+fn multipole_to_local<F: Float>(r: Tensor1<F>, src: &Node<F>) -> Local<F> {
+    let r_sq = r[0] * r[0] + r[1] * r[1] + r[2] * r[2];
+    let r1 = r_sq.sqrt();
+    let r3 = r_sq * r1;
+    let r5 = r3 * r_sq;
+    let r7 = r5 * r_sq;
+    let r9 = r7 * r_sq;
+
+    let c3 = F::from_f64(3.);
+    let c6 = F::from_f64(6.);
+    let c15 = F::from_f64(15.);
+    let c30 = F::from_f64(30.);
+    let c105 = F::from_f64(105.);
+    let sixth = F::from_f64(1. / 6.);
+
+    let [xx, xy, xz, yy, yz, zz] = src.quadrupole;
+    let q = [[xx, xy, xz], [xy, yy, yz], [xz, yz, zz]];
+    let m = src.mass;
+    let p = to_arr::<F>(src.dipole_about_center_of_mass());
+
+    // Q·r, and r·Q·r.
+    let qr: Tensor1<F> = std::array::from_fn(|i| q[i][0] * r[0] + q[i][1] * r[1] + q[i][2] * r[2]);
+    let rqr = qr[0] * r[0] + qr[1] * r[1] + qr[2] * r[2];
+
+    let mut result = Local::new_zero();
+
+    for i in 0..3 {
+        // Monopole, with contractions of the traceless quadrupole against derivatives of 1/r.
+        result.c1[i] = -m * r[i] / r3 + sixth * (-c15 * r[i] * rqr / r7 + c6 * qr[i] / r5);
+
+        for j in 0..3 {
+            let d2 = (c3 * r[i] * r[j] - r_sq * delta::<F>(i, j)) / r5;
+            let q_d4 = c105 * r[i] * r[j] * rqr / r9
+                - (c30 * (r[i] * qr[j] + r[j] * qr[i]) + c15 * delta::<F>(i, j) * rqr) / r7
+                + c6 * q[i][j] / r5;
+
+            result.c2[i][j] = m * d2 + sixth * q_d4;
+
+            // Dipole: -p·∇ of the monopole terms.
+            result.c1[i] -= p[j] * d2;
+
+            for k in 0..3 {
+                let d3 = -c15 * r[i] * r[j] * r[k] / r7
+                    + c3 * (r[i] * delta::<F>(j, k)
+                        + r[j] * delta::<F>(i, k)
+                        + r[k] * delta::<F>(i, j))
+                        / r5;
+
+                result.c3[i][j][k] = m * d3;
+                result.c2[i][j] -= p[k] * d3;
+            }
+        }
+    }
+
+    result
+}
+
+/// The radius of a sphere about the node's center of mass that encloses its bounding box.
+fn src_radius<F: Float>(node: &Node<F>) -> F {
+    let half_diag = node.bounding_box.width * F::from_f64(3.0_f64.sqrt() / 2.);
+    (node.center_of_mass - node.bounding_box.center).magnitude() + half_diag
+}
+
+/// Calculate force on every body used to build the tree, using the Fast Multipole Method. Results are
+/// indexed by body id, i.e. the index in the body array used to make the tree. `config.θ` sets the
+/// acceptance criterion for node pairs: `(r_target + r_source) / dist < θ`.
+///
+/// Each result, and `coupling`, are as for [`run_bh_multipole`](crate::run_bh_multipole).
+pub fn run_fmm<F: Float>(tree: &Tree<F>, config: &BhConfig<F>, coupling: F) -> Vec<F::Vec3> {
+    if tree.nodes.is_empty() {
+        return Vec::new();
+    }
+
+    let mut locals = vec![Local::new_zero(); tree.nodes.len()];
+    // Direct-sum contributions, in tree order.
+    let mut direct = vec![F::Vec3::new_zero(); tree.bodies.len()];
+
+    let half_diag = F::from_f64(3.0_f64.sqrt() / 2.);
+    let radii_src: Vec<F> = tree.nodes.iter().map(src_radius).collect();
+
+    // Dual-tree traversal, over (target, source) node pairs.
+    let mut stack = vec![(0, 0)];
+
+    while let Some((tgt_i, src_i)) = stack.pop() {
+        let tgt = &tree.nodes[tgt_i];
+        let src = &tree.nodes[src_i];
+
+        let r = tgt.bounding_box.center - src.center_of_mass;
+        let dist = r.magnitude();
+        let radius_tgt = tgt.bounding_box.width * half_diag;
+
+        if tgt_i != src_i && radius_tgt + radii_src[src_i] < config.θ * dist {
+            let local = multipole_to_local(to_arr::<F>(r), src);
+            locals[tgt_i].add(&local);
+            continue;
+        }
+
+        match (tgt.children.is_empty(), src.children.is_empty()) {
+            (true, true) => {
+                for i_tgt in tgt.body_range.clone() {
+                    let posit_tgt = tree.bodies[i_tgt].posit;
+                    for i_src in src.body_range.clone() {
+                        if i_src == i_tgt {
+                            continue;
+                        }
+                        let body = &tree.bodies[i_src];
+                        let diff = body.posit - posit_tgt;
+                        let dist = diff.magnitude();
+                        // Coincident bodies have no direction; they contribute nothing.
+                        if dist == F::zero() {
+                            continue;
+                        }
+                        direct[i_tgt] += diff * (body.mass / dist.powi(3));
+                    }
+                }
+            }
+            // Split the larger node; split the target if they're equal.
+            (false, true) => stack.extend(tgt.children.iter().map(|&c| (c, src_i))),
+            (true, false) => stack.extend(src.children.iter().map(|&c| (tgt_i, c))),
+            (false, false) => {
+                if src.bounding_box.width > tgt.bounding_box.width {
+                    stack.extend(src.children.iter().map(|&c| (tgt_i, c)));
+                } else {
+                    stack.extend(tgt.children.iter().map(|&c| (c, src_i)));
+                }
+            }
+        }
+    }
+
+    // Translate local expansions down the tree. Parents always precede their children.
+    for i in 0..tree.nodes.len() {
+        let node = &tree.nodes[i];
+        let local = locals[i];
+        for &child_i in &node.children {
+            let s = tree.nodes[child_i].bounding_box.center - node.bounding_box.center;
+            locals[child_i].add(&local.shifted(to_arr::<F>(s)));
+        }
+    }
+
+    let mut result = vec![F::Vec3::new_zero(); tree.bodies.len()];
+
+    for (node, local) in tree.nodes.iter().zip(&locals) {
+        if !node.children.is_empty() {
+            continue;
+        }
+        for i in node.body_range.clone() {
+            let y = tree.bodies[i].posit - node.bounding_box.center;
+            let field = local.eval(to_arr::<F>(y)) + direct[i];
+            result[tree.body_ids[i]] = field * coupling;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cube, ExpansionOrder, run_direct, test_utils};
+
+    /// RMS error of `run_fmm` relative to `run_direct`, normalized by the RMS direct field.
+    fn rms_error(order: ExpansionOrder) -> f64 {
+        let bodies = test_utils::bodies(400);
+        let config = BhConfig {
+            θ: 0.7,
+            max_bodies_per_node: 2,
+            expansion_order: order,
+            ..Default::default()
+        };
+
+        let bb = Cube::from_bodies(&bodies, 0.01, false).unwrap();
+        let tree = Tree::new(&bodies, &bb, &config);
+        let fmm = run_fmm(&tree, &config, 1.);
+
+        let force_fn = |acc_dir, mass_src, dist: f64| acc_dir * (mass_src / dist.powi(2));
+
+        let mut err_sq = 0.;
+        let mut direct_sq = 0.;
+        for (i, body) in bodies.iter().enumerate() {
+            let direct = run_direct(body.posit, i, &bodies, &force_fn);
+            err_sq += (fmm[i] - direct).magnitude_squared();
+            direct_sq += direct.magnitude_squared();
+        }
+
+        (err_sq / direct_sq).sqrt()
+    }
+
+    #[test]
+    fn matches_direct_sum() {
+        let monopole = rms_error(ExpansionOrder::Monopole);
+        let quadrupole = rms_error(ExpansionOrder::Quadrupole);
+
+        assert!(monopole < 1e-2, "Monopole RMS error: {monopole}");
+        assert!(quadrupole < 5e-3, "Quadrupole RMS error: {quadrupole}");
+        assert!(
+            quadrupole < 0.6 * monopole,
+            "Monopole: {monopole}, quadrupole: {quadrupole}"
+        );
+    }
+}
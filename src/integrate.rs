@@ -0,0 +1,438 @@
+//! Time integration of bodies under forces from the tree, and a `Simulation` driver that manages the
+//! bounding box and tree across steps. This replaces the usual application loop: Update the `Cube`, build
+//! the `Tree`, evaluate force on each body, then integrate.
+
+use rayon::prelude::*;
+
+use crate::{BhConfig, BodyModel, Cube, Float, Tree, Vector};
+
+/// A body that can be moved by an integrator. `force_fn` results are treated as acceleration.
+pub trait DynamicBody<F: Float = f64>: BodyModel<F> {
+    fn velocity(&self) -> F::Vec3;
+    fn set_posit(&mut self, posit: F::Vec3);
+    fn set_velocity(&mut self, velocity: F::Vec3);
+}
+
+/// A time integration scheme. On entry to `step`, `accel` holds accelerations at the current positions;
+/// the integrator must leave it holding accelerations at the new positions. This lets KDK schemes reuse
+/// the final evaluation of one step as the first of the next.
+pub trait Integrator<F: Float = f64> {
+    /// Advance `bodies` by `dt`. `compute_accel` returns accelerations for all bodies, at their current
+    /// positions.
+    fn step<T, A>(&self, bodies: &mut [T], accel: &mut Vec<F::Vec3>, dt: F, compute_accel: &mut A)
+    where
+        T: DynamicBody<F>,
+        A: FnMut(&[T]) -> Vec<F::Vec3>;
+
+    /// The number of acceleration evaluations per step.
+    fn evals_per_step(&self) -> usize;
+}
+
+/// One kick-drift-kick step, of length `dt`.
+fn kdk<F, T, A>(bodies: &mut [T], accel: &mut Vec<F::Vec3>, dt: F, compute_accel: &mut A)
+where
+    F: Float,
+    T: DynamicBody<F>,
+    A: FnMut(&[T]) -> Vec<F::Vec3>,
+{
+    let dt_half = dt / F::from_f64(2.);
+
+    for (body, a) in bodies.iter_mut().zip(accel.iter()) {
+        let velocity = body.velocity() + *a * dt_half;
+        body.set_velocity(velocity);
+        body.set_posit(body.posit() + velocity * dt);
+    }
+
+    *accel = compute_accel(bodies);
+
+    for (body, a) in bodies.iter_mut().zip(accel.iter()) {
+        body.set_velocity(body.velocity() + *a * dt_half);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+/// Kick-drift-kick leapfrog. Second order, and symplectic, with one acceleration evaluation per step.
+pub struct Leapfrog;
+
+impl<F: Float> Integrator<F> for Leapfrog {
+    fn step<T, A>(&self, bodies: &mut [T], accel: &mut Vec<F::Vec3>, dt: F, compute_accel: &mut A)
+    where
+        T: DynamicBody<F>,
+        A: FnMut(&[T]) -> Vec<F::Vec3>,
+    {
+        kdk(bodies, accel, dt, compute_accel);
+    }
+
+    fn evals_per_step(&self) -> usize {
+        1
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+/// Yoshida's 4th order symplectic integrator, composed of three leapfrog steps. It uses three acceleration
+/// evaluations per step.
+pub struct Yoshida4;
+
+impl<F: Float> Integrator<F> for Yoshida4 {
+    fn step<T, A>(&self, bodies: &mut [T], accel: &mut Vec<F::Vec3>, dt: F, compute_accel: &mut A)
+    where
+        T: DynamicBody<F>,
+        A: FnMut(&[T]) -> Vec<F::Vec3>,
+    {
+        let cbrt_2 = 2_f64.cbrt();
+        let w1 = F::from_f64(1. / (2. - cbrt_2));
+        let w0 = F::from_f64(-cbrt_2 / (2. - cbrt_2));
+
+        for w in [w1, w0, w1] {
+            kdk(bodies, accel, dt * w, compute_accel);
+        }
+    }
+
+    fn evals_per_step(&self) -> usize {
+        3
+    }
+}
+
+/// The finest level allowed by `BlockTimesteps`. Each step takes `2^max_level` sub-steps, each drifting all
+/// bodies; this is about a million.
+const MAX_BLOCK_LEVEL: u32 = 20;
+
+#[derive(Clone, Debug)]
+/// Parameters for hierarchical block time steps, used by `Simulation::step_block`. Each body steps with
+/// `dt / 2^level`, with its level chosen from the acceleration criterion `dt_i = sqrt(2 η softening / |a|)`,
+/// as in GADGET.
+pub struct BlockTimesteps<F: Float = f64> {
+    /// The finest level; the smallest time step is `dt / 2^max_level`. Values above 20 are treated as 20.
+    pub max_level: u32,
+    /// Accuracy parameter. Lower values result in smaller time steps. 0.025 is a common value.
+    pub η: F,
+    /// The gravitational softening length. (Or another length scale of the system)
+    pub softening: F,
+}
+
+impl<F: Float> Default for BlockTimesteps<F> {
+    fn default() -> Self {
+        Self {
+            max_level: 8,
+            η: F::from_f64(0.025),
+            softening: F::from_f64(0.01),
+        }
+    }
+}
+
+impl<F: Float> BlockTimesteps<F> {
+    /// `max_level`, limited to one we can count sub-steps for.
+    fn max_level_clamped(&self) -> u32 {
+        self.max_level.min(MAX_BLOCK_LEVEL)
+    }
+
+    /// The level for a body with acceleration `accel`: The coarsest with a time step no larger than the
+    /// criterion's.
+    pub fn level(&self, accel: F::Vec3, dt: F) -> u32 {
+        let accel_mag = accel.magnitude();
+        if accel_mag == F::zero() {
+            return 0;
+        }
+
+        let dt_criterion = (F::from_f64(2.) * self.η * self.softening / accel_mag).sqrt();
+
+        let mut level = 0;
+        let mut dt_level = dt;
+        while level < self.max_level_clamped() && dt_level > dt_criterion {
+            level += 1;
+            dt_level /= F::from_f64(2.);
+        }
+
+        level
+    }
+}
+
+/// Runs a simulation: Steps bodies forward in time using an integrator, evaluating acceleration with
+/// `Tree::run_bh_batch`. The driver keeps a bounding box padded by `bb_pad`, and updates the tree in place
+/// using `Tree::refit` while bodies remain in it; it rebuilds both when bodies leave it. For
+/// `OpeningCriterion::RelativeForce`, it passes each body's previous acceleration; see `Tree::acc_prev_from`.
+///
+/// This uses open boundaries; `new` panics if `BhConfig::periodic` is set.
+pub struct Simulation<T, I, K, F: Float = f64> {
+    pub bodies: Vec<T>,
+    pub config: BhConfig<F>,
+    pub integrator: I,
+    /// Returns acceleration; e.g. from the `kernels` module. Has the same signature as for `run_bh`.
+    pub force_fn: K,
+    pub dt: F,
+    /// Passed to `Cube::from_bodies`. Larger values mean fewer tree rebuilds, at the cost of a deeper tree.
+    pub bb_pad: F,
+    /// Elapsed simulation time.
+    pub time: F,
+    /// Accelerations at the current body positions, by body index.
+    pub(crate) accel: Vec<F::Vec3>,
+    /// Time step levels, by body index, for `step_block`.
+    pub(crate) levels: Vec<u32>,
+    pub(crate) tree: Option<Tree<F>>,
+    /// The number of times the bounding box, and tree, were rebuilt from scratch.
+    pub(crate) rebuilds: usize,
+}
+
+impl<T, I, K, F> Simulation<T, I, K, F>
+where
+    F: Float,
+    T: DynamicBody<F> + Sync,
+    I: Integrator<F>,
+    K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+{
+    /// Set up a simulation, building the tree, and computing initial accelerations. Panics if
+    /// `config.periodic` is set.
+    pub fn new(
+        bodies: Vec<T>,
+        config: BhConfig<F>,
+        integrator: I,
+        force_fn: K,
+        dt: F,
+        bb_pad: F,
+    ) -> Self {
+        assert!(
+            !config.periodic,
+            "Simulation doesn't support periodic boundaries; its bounding box is padded, and rebuilt as bodies move."
+        );
+
+        let mut result = Self {
+            bodies,
+            config,
+            integrator,
+            force_fn,
+            dt,
+            bb_pad,
+            time: F::zero(),
+            accel: Vec::new(),
+            levels: Vec::new(),
+            tree: None,
+            rebuilds: 0,
+        };
+
+        result.accel = compute_accel(
+            &result.bodies,
+            &[],
+            &mut result.tree,
+            &mut result.rebuilds,
+            &result.config,
+            &result.force_fn,
+            result.bb_pad,
+        );
+
+        result
+    }
+
+    /// Advance the simulation by one time step.
+    pub fn step(&mut self) {
+        let Self {
+            bodies,
+            config,
+            integrator,
+            force_fn,
+            dt,
+            bb_pad,
+            accel,
+            tree,
+            rebuilds,
+            ..
+        } = self;
+
+        // The integrator holds `accel` while evaluating, so we keep our own copy for the opening criterion.
+        let mut accel_prev = accel.clone();
+        let mut compute = |bodies: &[T]| {
+            let result = compute_accel(
+                bodies,
+                &accel_prev,
+                tree,
+                rebuilds,
+                config,
+                force_fn,
+                *bb_pad,
+            );
+            accel_prev.clone_from(&result);
+            result
+        };
+
+        if accel.len() != bodies.len() {
+            *accel = compute(bodies);
+        }
+
+        integrator.step(bodies, accel, *dt, &mut compute);
+        self.time += self.dt;
+    }
+
+    /// Advance the simulation by one time step, `dt`, using hierarchical block time steps: Each body steps with
+    /// `dt / 2^level`, with KDK leapfrog. At each sub-step, all bodies drift, and the tree is refit; only bodies
+    /// ending their step have acceleration re-evaluated. Levels are updated at the end of each body's step.
+    /// This ignores `integrator`.
+    pub fn step_block(&mut self, timesteps: &BlockTimesteps<F>) {
+        let Self {
+            bodies,
+            config,
+            force_fn,
+            dt,
+            bb_pad,
+            accel,
+            levels,
+            tree,
+            rebuilds,
+            ..
+        } = self;
+
+        if accel.len() != bodies.len() {
+            *accel = compute_accel(bodies, &[], tree, rebuilds, config, force_fn, *bb_pad);
+        }
+
+        if levels.len() != bodies.len() {
+            *levels = accel.iter().map(|a| timesteps.level(*a, *dt)).collect();
+        }
+
+        let max_level = timesteps.max_level_clamped();
+        for level in levels.iter_mut() {
+            *level = (*level).min(max_level);
+        }
+
+        // We track time as integer ticks of the smallest step.
+        let num_ticks: u64 = 1 << max_level;
+        let ticks_per_step = |level: u32| 1_u64 << (max_level - level);
+
+        let dt_min = *dt / F::from_f64(num_ticks as f64);
+        let half = F::from_f64(0.5);
+
+        for tick in 1..=num_ticks {
+            // Opening half-kicks, for bodies starting a step.
+            for ((body, a), &level) in bodies.iter_mut().zip(accel.iter()).zip(levels.iter()) {
+                let ticks = ticks_per_step(level);
+                if (tick - 1) % ticks == 0 {
+                    let dt_body = dt_min * F::from_f64(ticks as f64);
+                    body.set_velocity(body.velocity() + *a * (dt_body * half));
+                }
+            }
+
+            for body in bodies.iter_mut() {
+                body.set_posit(body.posit() + body.velocity() * dt_min);
+            }
+
+            let active: Vec<usize> = (0..bodies.len())
+                .filter(|&i| tick % ticks_per_step(levels[i]) == 0)
+                .collect();
+
+            if active.is_empty() {
+                continue;
+            }
+
+            update_tree(bodies, tree, rebuilds, config, *bb_pad);
+            let Some(t) = tree.as_ref() else {
+                return;
+            };
+
+            let coupling = t.coupling_estimate(force_fn);
+            let accel_active: Vec<F::Vec3> = active
+                .par_iter()
+                .map(|&i| {
+                    let acc_prev = coupling.map(|c| accel[i].magnitude() / c);
+                    t.force_serial(bodies[i].posit(), i, acc_prev, config, force_fn)
+                })
+                .collect();
+
+            // Closing half-kicks, and new levels.
+            for (&i, a) in active.iter().zip(accel_active) {
+                let dt_body = dt_min * F::from_f64(ticks_per_step(levels[i]) as f64);
+                let body = &mut bodies[i];
+                body.set_velocity(body.velocity() + a * (dt_body * half));
+                accel[i] = a;
+
+                // Coarser steps must start at a multiple of their length.
+                let mut level = timesteps.level(a, *dt);
+                while level < levels[i] && tick % ticks_per_step(level) != 0 {
+                    level += 1;
+                }
+                levels[i] = level;
+            }
+        }
+
+        self.time += self.dt;
+    }
+
+    /// Advance the simulation by `num_steps` time steps.
+    pub fn run(&mut self, num_steps: usize) {
+        for _ in 0..num_steps {
+            self.step();
+        }
+    }
+
+    /// Accelerations at the current body positions, by body index.
+    pub fn accelerations(&self) -> &[F::Vec3] {
+        &self.accel
+    }
+
+    /// The tree from the most recent acceleration evaluation.
+    pub fn tree(&self) -> Option<&Tree<F>> {
+        self.tree.as_ref()
+    }
+
+    /// Time step levels from the most recent `step_block`, by body index. Each body's step is `dt / 2^level`.
+    pub fn levels(&self) -> &[u32] {
+        &self.levels
+    }
+
+    /// The number of times the bounding box, and tree, were rebuilt from scratch, instead of refit.
+    pub fn rebuilds(&self) -> usize {
+        self.rebuilds
+    }
+}
+
+/// Update the tree for the bodies' current positions, and compute acceleration on each. `accel_prev` holds
+/// accelerations from the previous evaluation, for `OpeningCriterion::RelativeForce`; it may be empty.
+fn compute_accel<F, T, K>(
+    bodies: &[T],
+    accel_prev: &[F::Vec3],
+    tree: &mut Option<Tree<F>>,
+    rebuilds: &mut usize,
+    config: &BhConfig<F>,
+    force_fn: &K,
+    bb_pad: F,
+) -> Vec<F::Vec3>
+where
+    F: Float,
+    T: BodyModel<F> + Sync,
+    K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+{
+    update_tree(bodies, tree, rebuilds, config, bb_pad);
+
+    match tree {
+        Some(t) => {
+            let acc_prev = if accel_prev.len() == bodies.len() {
+                t.acc_prev_from(accel_prev, force_fn)
+            } else {
+                None
+            };
+            t.run_bh_batch_with_acc_prev(bodies, true, acc_prev.as_deref(), config, force_fn)
+        }
+        None => vec![F::Vec3::new_zero(); bodies.len()],
+    }
+}
+
+/// Update the tree for the bodies' current positions. We refit the existing tree if all bodies are
+/// still in its bounding box; otherwise, we rebuild both. The tree is `None` if there are no bodies.
+fn update_tree<F, T>(
+    bodies: &[T],
+    tree: &mut Option<Tree<F>>,
+    rebuilds: &mut usize,
+    config: &BhConfig<F>,
+    bb_pad: F,
+) where
+    F: Float,
+    T: BodyModel<F> + Sync,
+{
+    let refit = match tree {
+        Some(t) if t.bodies.len() == bodies.len() => t.refit(bodies, config).escaped.is_empty(),
+        _ => false,
+    };
+
+    if !refit {
+        *tree = Cube::from_bodies(bodies, bb_pad, false)
+            .map(|cube| Tree::new_par(bodies, &cube, config));
+        *rebuilds += 1;
+    }
+}
@@ -0,0 +1,323 @@
+//! Force laws, for use as the `force_fn` parameter of `run_bh`, `run_bh_batch` etc. Each function returns
+//! a closure with signature `(acc_dir: Vec3 (unit), mass_src, dist) -> Vec3`.
+//!
+//! `plummer`, `spline`, and `gaussian` are softened 1/r^2 laws. Softening prevents large forces, and
+//! integration error, in close encounters between bodies. They take a softening length; results are
+//! `mass_src * acc_dir / dist^2` for distances large compared to it. Multiply by your coupling constant
+//! (e.g. G) as required.
+//!
+//! Softening lengths are Plummer-equivalent: Each kernel's potential at `dist = 0` matches that of `plummer`,
+//! with the same length. At `dist = 0`, where `acc_dir` is undefined, each returns zero.
+
+use std::f64::consts::PI;
+
+use crate::{Float, Vector, ewald::erfc};
+
+/// The cubic spline's support radius, in units of its Plummer-equivalent softening length. (From GADGET)
+const SPLINE_SUPPORT_RATIO: f64 = 2.8;
+
+/// Plummer softening: The force from a Plummer sphere, `mass_src * dist / (dist^2 + softening^2)^(3/2)`.
+/// Simple, but converges slowly to 1/r^2, even at several softening lengths.
+pub fn plummer<F: Float>(softening: F) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    let softening_sq = softening.powi(2);
+
+    move |acc_dir, mass_src, dist| {
+        if dist == F::zero() {
+            return F::Vec3::new_zero();
+        }
+
+        acc_dir * (mass_src * dist / (dist.powi(2) + softening_sq).powf(F::from_f64(1.5)))
+    }
+}
+
+/// The cubic spline kernel of Monaghan and Lattanzio, as used in GADGET. Force is exactly Newtonian
+/// beyond `2.8 * softening`.
+pub fn spline<F: Float>(softening: F) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    let h = softening * F::from_f64(SPLINE_SUPPORT_RATIO);
+    let h_inv3 = F::one() / h.powi(3);
+
+    move |acc_dir, mass_src, dist| {
+        if dist == F::zero() {
+            return F::Vec3::new_zero();
+        }
+
+        let u = dist / h;
+
+        let c = |v: f64| F::from_f64(v);
+
+        // Force divided by `mass_src * dist`.
+        let factor = if u < c(0.5) {
+            h_inv3 * (c(32. / 3.) + u.powi(2) * (c(32.) * u - c(38.4)))
+        } else if u < F::one() {
+            h_inv3
+                * (c(64. / 3.) - c(48.) * u + c(38.4) * u.powi(2)
+                    - c(32. / 3.) * u.powi(3)
+                    - c(1. / 15.) / u.powi(3))
+        } else {
+            F::one() / dist.powi(3)
+        };
+
+        acc_dir * (mass_src * dist * factor)
+    }
+}
+
+/// Gaussian softening: The force from a source with a Gaussian density profile, with standard deviation
+/// `softening * sqrt(2 / π)`.
+pub fn gaussian<F: Float>(softening: F) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    let std_dev = softening * F::from_f64((2. / PI).sqrt());
+
+    move |acc_dir, mass_src, dist| {
+        if dist == F::zero() {
+            return F::Vec3::new_zero();
+        }
+
+        let u = (dist / std_dev).to_f64().unwrap_or(0.);
+
+        // The fraction of the source's mass within `dist`.
+        let enclosed = if u < 1. {
+            // Series expansion, avoiding cancellation at small `u`:
+            // Σ (-1)^n u^(2n + 3) / (2^n n! (2n + 3))
+            let mut term = u.powi(3);
+            let mut sum = 0.;
+            for n in 0..12 {
+                sum += term / (2 * n + 3) as f64;
+                term *= -u.powi(2) / (2. * (n + 1) as f64);
+            }
+            (2. / PI).sqrt() * sum
+        } else {
+            1. - erfc(u / 2_f64.sqrt()) - (2. / PI).sqrt() * u * (-u.powi(2) / 2.).exp()
+        };
+
+        acc_dir * (mass_src * F::from_f64(enclosed) / dist.powi(2))
+    }
+}
+
+/// Newtonian gravity: `g * mass_src * acc_dir / dist^2`, where `g` is the gravitational constant, in your
+/// units. Returns acceleration.
+pub fn newton<F: Float>(g: F) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    move |acc_dir, mass_src, dist| acc_dir * (g * mass_src / dist.powi(2))
+}
+
+/// Coulomb's law: The electric field, `-k * charge_src * acc_dir / dist^2`, where `k` is Coulomb's constant,
+/// in your units. Pass charge in place of mass. Multiply by the target's charge for force.
+///
+/// The field points away from positive sources; `acc_dir` points from the target to the source.
+pub fn coulomb<F: Float>(k: F) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    move |acc_dir, charge_src, dist| -acc_dir * (k * charge_src / dist.powi(2))
+}
+
+/// A Yukawa, or Debye-screened Coulomb field: The gradient of `k * charge_src * e^(-dist / screening_len) / dist`.
+/// As with `coulomb`, this is the field, pointing away from positive sources; multiply by the target's charge
+/// for force. For an attractive Yukawa potential, e.g. between nucleons, use a negative `k`.
+pub fn yukawa<F: Float>(
+    k: F,
+    screening_len: F,
+) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    move |acc_dir, charge_src, dist| {
+        let screening = (-dist / screening_len).exp();
+        -acc_dir
+            * (k * charge_src
+                * screening
+                * (F::one() / dist.powi(2) + F::one() / (screening_len * dist)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// Interpolation functions ν(y) for MOND, where y is the Newtonian acceleration divided by `a0`. The MOND
+/// acceleration is `ν(y) * a_newton`. Each approaches 1 for `y >> 1`, and `1 / sqrt(y)` for `y << 1`.
+pub enum MondInterpolation {
+    /// ν(y) = 1/2 + sqrt(1/4 + 1/y)
+    #[default]
+    Simple,
+    /// ν(y) = sqrt(1/2 + sqrt(1/4 + 1/y^2))
+    Standard,
+    /// ν(y) = 1 / (1 - e^(-sqrt(y))). (McGaugh, from the radial acceleration relation)
+    Rar,
+}
+
+impl MondInterpolation {
+    /// Evaluate ν(y).
+    pub fn nu<F: Float>(self, y: F) -> F {
+        let quarter = F::from_f64(0.25);
+
+        match self {
+            Self::Simple => F::from_f64(0.5) + (quarter + F::one() / y).sqrt(),
+            Self::Standard => (F::from_f64(0.5) + (quarter + F::one() / y.powi(2)).sqrt()).sqrt(),
+            Self::Rar => F::one() / (F::one() - (-y.sqrt()).exp()),
+        }
+    }
+}
+
+/// Newtonian gravity, modified by MOND: `ν(a_newton / a0) * a_newton`, where `a_newton` is from `newton`,
+/// and `a0` is MOND's acceleration scale; about 1.2e-10 m/s^2.
+///
+/// Note that MOND is nonlinear; this applies the interpolation function to each source (or grouped node)
+/// separately. For the total field, sum Newtonian accelerations (e.g. using `newton`), and apply
+/// `MondInterpolation::nu` to the result.
+pub fn mond<F: Float>(
+    g: F,
+    a0: F,
+    interpolation: MondInterpolation,
+) -> impl Fn(F::Vec3, F, F) -> F::Vec3 + Copy + Send + Sync {
+    move |acc_dir, mass_src, dist| {
+        let a_newton = g * mass_src / dist.powi(2);
+        acc_dir * (interpolation.nu(a_newton / a0) * a_newton)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lin_alg::f64::Vec3;
+
+    use super::*;
+
+    /// Source on the +x axis from the target, at `dist`.
+    const DIR: Vec3 = Vec3 {
+        x: 1.,
+        y: 0.,
+        z: 0.,
+    };
+
+    fn assert_close(actual: f64, expected: f64) {
+        let tol = 1e-10 * expected.abs().max(1e-300);
+        assert!(
+            (actual - expected).abs() <= tol,
+            "actual: {actual}, expected: {expected}"
+        );
+    }
+
+    #[test]
+    fn newton_two_body() {
+        let (g, mass, dist): (f64, f64, f64) = (6.674e-11, 5.972e24, 6.371e6);
+        let result = newton(g)(DIR, mass, dist);
+
+        // Attractive: toward the source.
+        assert_close(result.x, g * mass / dist.powi(2));
+        assert_eq!((result.y, result.z), (0., 0.));
+    }
+
+    #[test]
+    fn coulomb_two_body_sign() {
+        let (k, dist): (f64, f64) = (8.988e9, 0.5);
+
+        // The field points away from positive sources, and toward negative ones.
+        assert_close(coulomb(k)(DIR, 2e-6, dist).x, -k * 2e-6 / dist.powi(2));
+        assert_close(coulomb(k)(DIR, -2e-6, dist).x, k * 2e-6 / dist.powi(2));
+
+        // Force on a target of like charge is repulsive; on one of unlike charge, attractive.
+        let field = coulomb(k)(DIR, 1e-6, dist);
+        assert!((field * 1e-6).x < 0.);
+        assert!((field * -1e-6).x > 0.);
+    }
+
+    #[test]
+    fn yukawa_two_body() {
+        let (k, charge, screening_len): (f64, f64, f64) = (2., 3., 0.7);
+        let potential = |r: f64| k * charge * (-r / screening_len).exp() / r;
+
+        for dist in [0.1, 0.7, 2.5] {
+            let result = yukawa(k, screening_len)(DIR, charge, dist);
+
+            let expected = -k
+                * charge
+                * (-dist / screening_len).exp()
+                * (1. / dist.powi(2) + 1. / (screening_len * dist));
+            assert_close(result.x, expected);
+
+            // The field is -∇φ at the target; the source is at +x, so this is dφ/dr along x.
+            let h = 1e-6 * dist;
+            let numeric = (potential(dist + h) - potential(dist - h)) / (2. * h);
+            assert!((result.x - numeric).abs() < 1e-6 * numeric.abs());
+
+            // Points away from a positive source.
+            assert!(result.x < 0.);
+        }
+
+        // Without screening, this reduces to Coulomb.
+        let unscreened = yukawa(k, 1e12)(DIR, charge, 1.5).x;
+        assert!((unscreened - coulomb(k)(DIR, charge, 1.5).x).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mond_interpolation_closed_form() {
+        assert_close(MondInterpolation::Simple.nu(1.), 0.5 + 1.25_f64.sqrt());
+        assert_close(
+            MondInterpolation::Standard.nu(1.),
+            (0.5 + 1.25_f64.sqrt()).sqrt(),
+        );
+        assert_close(MondInterpolation::Rar.nu(1.), 1. / (1. - (-1_f64).exp()));
+    }
+
+    #[test]
+    fn mond_interpolation_limits() {
+        for interp in [
+            MondInterpolation::Simple,
+            MondInterpolation::Standard,
+            MondInterpolation::Rar,
+        ] {
+            // Newtonian regime: ν -> 1.
+            assert!((interp.nu(1e8_f64) - 1.).abs() < 1e-4, "{interp:?}");
+
+            // Deep MOND regime: ν -> 1 / sqrt(y).
+            let y: f64 = 1e-8;
+            assert!((interp.nu(y) * y.sqrt() - 1.).abs() < 1e-3, "{interp:?}");
+        }
+    }
+
+    #[test]
+    fn mond_two_body() {
+        let (g, a0, mass): (f64, f64, f64) = (6.674e-11, 1.2e-10, 2e30);
+
+        for interp in [
+            MondInterpolation::Simple,
+            MondInterpolation::Standard,
+            MondInterpolation::Rar,
+        ] {
+            let f = mond(g, a0, interp);
+
+            for dist in [1e9_f64, 1e14, 1e19] {
+                let a_newton = g * mass / dist.powi(2);
+                assert_close(f(DIR, mass, dist).x, interp.nu(a_newton / a0) * a_newton);
+            }
+
+            // Close in, this is Newtonian.
+            let dist: f64 = 1e9;
+            let a_newton = g * mass / dist.powi(2);
+            assert!((f(DIR, mass, dist).x / a_newton - 1.).abs() < 1e-4);
+
+            // Far out, it approaches sqrt(G M a0) / r.
+            let dist: f64 = 1e19;
+            let deep = (g * mass * a0).sqrt() / dist;
+            assert!((f(DIR, mass, dist).x / deep - 1.).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn softened_kernels() {
+        let (mass, softening): (f64, f64) = (3., 0.1);
+
+        for (name, result_0, result_far) in [
+            (
+                "plummer",
+                plummer(softening)(Vec3::new_zero(), mass, 0.),
+                plummer(softening)(DIR, mass, 1e3),
+            ),
+            (
+                "spline",
+                spline(softening)(Vec3::new_zero(), mass, 0.),
+                spline(softening)(DIR, mass, 1e3),
+            ),
+            (
+                "gaussian",
+                gaussian(softening)(Vec3::new_zero(), mass, 0.),
+                gaussian(softening)(DIR, mass, 1e3),
+            ),
+        ] {
+            // Finite, and zero, for coincident bodies.
+            assert_eq!((result_0.x, result_0.y, result_0.z), (0., 0., 0.), "{name}");
+
+            // Newtonian far away.
+            assert!((result_far.x / (mass / 1e6) - 1.).abs() < 1e-6, "{name}");
+        }
+    }
+}
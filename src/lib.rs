@@ -2,60 +2,119 @@
 //! computation time, where `N` is the number of bodies. Canonical use cases include gravity, and charged
 //! particle simulations.
 //!
+//! Generic over `f32` and `f64`, via the `Float` trait. The default is `f64`.
+//!
 //! See the [readme](https://github.com/David-OConnor/barnes_hut/blob/main/README.md) for details,
 //! including an example.
 
 #![allow(non_ascii_idents)]
 #![allow(mixed_script_confusables)]
 
-// todo: Ideally make generic over f32 and f64, but we don't have a good way to do that with `Vec3`.
+mod accuracy;
+#[cfg(feature = "encode")]
+mod checkpoint;
+#[cfg(feature = "encode")]
+mod encode;
+mod ewald;
+mod float;
+mod fmm;
+mod integrate;
+pub mod kernels;
+mod multipole;
+mod opening;
+mod query;
+mod refit;
+#[cfg(feature = "serde")]
+mod serde_vec3;
+mod stats;
+#[cfg(test)]
+mod test_utils;
+mod vtk;
 
-use std::{fmt, fmt::Formatter};
+use std::{fmt, fmt::Formatter, ops::Range};
 
+pub use accuracy::{AccuracyReport, run_direct};
 #[cfg(feature = "encode")]
-use bincode::{Decode, Encode};
-use lin_alg::f64::Vec3;
+pub use checkpoint::{CHECKPOINT_VERSION, CheckpointError};
+pub use ewald::{EwaldTable, run_bh_ewald};
+pub use float::{Encodable, Float, Vector};
+pub use fmm::run_fmm;
+pub use integrate::{BlockTimesteps, DynamicBody, Integrator, Leapfrog, Simulation, Yoshida4};
+pub use multipole::{ExpansionOrder, Octupole, Quadrupole, run_bh_dipole, run_bh_multipole};
+pub use opening::OpeningCriterion;
 use rayon::prelude::*;
+pub use refit::RefitReport;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+pub use stats::{InteractionCount, TreeStats};
 
 #[derive(Clone, Debug)]
-#[cfg_attr(feature = "bincode", derive(Encode, Decode))]
-pub struct BhConfig {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BhConfig<F: Float = f64> {
     /// This determines how aggressively we group. It's no lower than 0. 0 means no grouping.
     /// (Best accuracy; poorest performance; effectively a naive N-body). Higher values
     /// decrease accuracy, and are more performant.
-    pub θ: f64,
+    pub θ: F,
     pub max_bodies_per_node: usize,
     /// This is a limit on tree division, preventing getting stuck in a loop, e.g. for particles with close.
     /// (or identical) positions
     pub max_tree_depth: usize,
+    /// Multipole terms computed for each node, and applied by `run_bh_multipole`. `run_bh` always
+    /// uses the monopole only.
+    pub expansion_order: ExpansionOrder,
+    /// If true, treat the root `Cube` passed to `Tree::new` as a periodic box, using minimum-image distances
+    /// between targets and sources. For 1/r potentials, use `run_bh_ewald` to include the remaining periodic
+    /// images. Not supported by `run_fmm`.
+    pub periodic: bool,
+    /// How we decide if a node is far enough from a target to group it. Applies to all evaluation functions
+    /// except `run_fmm`. `OpeningCriterion::RelativeForce` uses each target's previous acceleration, passed
+    /// to `run_bh_with_acc_prev` or `Tree::run_bh_batch_with_acc_prev`; `Simulation` and `Tree::accuracy_report`
+    /// pass it automatically. Other evaluation functions use its fallback.
+    pub opening_criterion: OpeningCriterion,
+    /// The geometric θ used by `OpeningCriterion::RelativeForce` when a target's previous acceleration isn't
+    /// available, e.g. on the first step. `θ` for that criterion is on a different scale.
+    pub θ_fallback: F,
 }
 
-impl Default for BhConfig {
+impl<F: Float> Default for BhConfig<F> {
     fn default() -> Self {
         Self {
-            θ: 0.5,
+            θ: F::from_f64(0.5),
             max_bodies_per_node: 1,
             max_tree_depth: 15,
+            expansion_order: ExpansionOrder::Monopole,
+            periodic: false,
+            opening_criterion: OpeningCriterion::Geometric,
+            θ_fallback: F::from_f64(0.5),
         }
     }
 }
 
 /// We use this to allow for arbitrary body (or particle etc) types in application code to
 /// use this library. Substitute `charge` for `mass` as required.
-pub trait BodyModel {
-    fn posit(&self) -> Vec3;
-    fn mass(&self) -> f64;
+///
+/// `F` is `f64` or `f32`; `posit` returns `lin_alg::f64::Vec3` or `lin_alg::f32::Vec3` respectively.
+pub trait BodyModel<F: Float = f64> {
+    fn posit(&self) -> F::Vec3;
+    fn mass(&self) -> F;
 }
 
 #[derive(Clone, Debug)]
-#[cfg_attr(feature = "bincode", derive(Encode, Decode))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// A cubical bounding box. length=width=depth.
-pub struct Cube {
-    pub center: Vec3,
-    pub width: f64,
+pub struct Cube<F: Float = f64> {
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serde_vec3::serialize::<F, _>",
+            deserialize_with = "serde_vec3::deserialize::<F, _>"
+        )
+    )]
+    pub center: F::Vec3,
+    pub width: F,
 }
 
-impl Cube {
+impl<F: Float> Cube<F> {
     /// Construct minimum limits that encompass all bodies. Run these each time the bodies change,
     /// or perhaps use a pad and do it at a coarser interval.
     ///
@@ -64,26 +123,26 @@ impl Cube {
     ///
     /// The z offset is intended for the case where the Z coordinate for all particles is 0.
     /// This prevents the divisions straddling the points, doubling the number of nodes.
-    pub fn from_bodies<T: BodyModel>(bodies: &[T], pad: f64, z_offset: bool) -> Option<Self> {
+    pub fn from_bodies<T: BodyModel<F>>(bodies: &[T], pad: F, z_offset: bool) -> Option<Self> {
         if bodies.is_empty() {
             return None;
         }
 
-        let mut x_min = f64::MAX;
-        let mut x_max = f64::MIN;
-        let mut y_min = f64::MAX;
-        let mut y_max = f64::MIN;
-        let mut z_min = f64::MAX;
-        let mut z_max = f64::MIN;
+        let mut x_min = F::max_value();
+        let mut x_max = F::min_value();
+        let mut y_min = F::max_value();
+        let mut y_max = F::min_value();
+        let mut z_min = F::max_value();
+        let mut z_max = F::min_value();
 
         for body in bodies {
             let p = &body.posit();
-            x_min = x_min.min(p.x);
-            x_max = x_max.max(p.x);
-            y_min = y_min.min(p.y);
-            y_max = y_max.max(p.y);
-            z_min = z_min.min(p.z);
-            z_max = z_max.max(p.z);
+            x_min = x_min.min(p.x());
+            x_max = x_max.max(p.x());
+            y_min = y_min.min(p.y());
+            y_max = y_max.max(p.y());
+            z_min = z_min.min(p.z());
+            z_max = z_max.max(p.z());
         }
 
         x_min -= pad;
@@ -94,7 +153,7 @@ impl Cube {
         z_max += pad;
 
         if z_offset {
-            z_max += 1e-5;
+            z_max += F::from_f64(1e-5);
         }
 
         let x_size = x_max - x_min;
@@ -104,55 +163,134 @@ impl Cube {
         // Coerce to a cube.
         let width = x_size.max(y_size).max(z_size);
 
-        let center = Vec3::new(
-            (x_max + x_min) / 2.,
-            (y_max + y_min) / 2.,
-            (z_max + z_min) / 2.,
+        let two = F::from_f64(2.);
+        let center = F::Vec3::new(
+            (x_max + x_min) / two,
+            (y_max + y_min) / two,
+            (z_max + z_min) / two,
         );
 
         Some(Self::new(center, width))
     }
 
-    pub fn new(center: Vec3, width: f64) -> Self {
+    pub fn new(center: F::Vec3, width: F) -> Self {
         Self { center, width }
     }
 
+    /// If a position is inside this cube, or on its boundary.
+    pub fn contains(&self, posit: F::Vec3) -> bool {
+        let half = self.width / F::from_f64(2.);
+        let d = posit - self.center;
+
+        d.x().abs() <= half && d.y().abs() <= half && d.z().abs() <= half
+    }
+
     /// Divide this into equal-area octants.
     pub(crate) fn divide_into_octants(&self) -> [Self; 8] {
-        let width = self.width / 2.;
-        let wd2 = self.width / 4.; // short for brevity below.
+        let width = self.width / F::from_f64(2.);
+        let wd2 = self.width / F::from_f64(4.); // short for brevity below.
 
         // Every combination of + and - for the center offset.
         // The order matters, due to the binary index logic used when partitioning bodies into octants.
         [
-            Self::new(self.center + Vec3::new(-wd2, -wd2, -wd2), width),
-            Self::new(self.center + Vec3::new(wd2, -wd2, -wd2), width),
-            Self::new(self.center + Vec3::new(-wd2, wd2, -wd2), width),
-            Self::new(self.center + Vec3::new(wd2, wd2, -wd2), width),
-            Self::new(self.center + Vec3::new(-wd2, -wd2, wd2), width),
-            Self::new(self.center + Vec3::new(wd2, -wd2, wd2), width),
-            Self::new(self.center + Vec3::new(-wd2, wd2, wd2), width),
-            Self::new(self.center + Vec3::new(wd2, wd2, wd2), width),
+            Self::new(self.center + F::Vec3::new(-wd2, -wd2, -wd2), width),
+            Self::new(self.center + F::Vec3::new(wd2, -wd2, -wd2), width),
+            Self::new(self.center + F::Vec3::new(-wd2, wd2, -wd2), width),
+            Self::new(self.center + F::Vec3::new(wd2, wd2, -wd2), width),
+            Self::new(self.center + F::Vec3::new(-wd2, -wd2, wd2), width),
+            Self::new(self.center + F::Vec3::new(wd2, -wd2, wd2), width),
+            Self::new(self.center + F::Vec3::new(-wd2, wd2, wd2), width),
+            Self::new(self.center + F::Vec3::new(wd2, wd2, wd2), width),
         ]
     }
 }
 
 #[derive(Debug)]
-pub struct Node {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Node<F: Float = f64> {
     /// We use `id` while building the tree, then sort by it, replacing with index.
     /// Once complete, `id` == index in `Tree::nodes`.
-    /// Mass, center-of-mass, and body_ids include those from all sub-nodes.
+    /// Mass, center-of-mass, and body_range include those from all sub-nodes.
     pub id: usize,
-    pub bounding_box: Cube,
+    pub bounding_box: Cube<F>,
     /// Node indices in the tree. We use this to guide the transversal process while finding
     /// relevant nodes for a given target body.
     pub children: Vec<usize>,
-    pub mass: f64,
-    pub center_of_mass: Vec3,
-    pub body_ids: Vec<usize>,
+    /// Net mass, or charge.
+    pub mass: F,
+    /// The center of `|mass|`. For nodes whose bodies have the same sign, this is the usual center of
+    /// mass. Unlike the center of net charge, this is well-defined for neutral nodes.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serde_vec3::serialize::<F, _>",
+            deserialize_with = "serde_vec3::deserialize::<F, _>"
+        )
+    )]
+    pub center_of_mass: F::Vec3,
+    /// Total mass or charge of bodies with positive values.
+    pub mass_pos: F,
+    /// The center of bodies with positive mass or charge.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serde_vec3::serialize::<F, _>",
+            deserialize_with = "serde_vec3::deserialize::<F, _>"
+        )
+    )]
+    pub center_pos: F::Vec3,
+    /// Total mass or charge of bodies with negative values.
+    pub mass_neg: F,
+    /// The center of bodies with negative mass or charge.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serde_vec3::serialize::<F, _>",
+            deserialize_with = "serde_vec3::deserialize::<F, _>"
+        )
+    )]
+    pub center_neg: F::Vec3,
+    /// The dipole moment, `Σ mass * (posit - bounding_box.center)`. This is the leading term for nearly
+    /// neutral nodes of charges. Used by `run_bh_dipole`, and, via `dipole_about_center_of_mass`, by
+    /// `run_bh_multipole` and `run_fmm`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serde_vec3::serialize::<F, _>",
+            deserialize_with = "serde_vec3::deserialize::<F, _>"
+        )
+    )]
+    pub dipole: F::Vec3,
+    /// Traceless, about `center_of_mass`. Zero unless built with `ExpansionOrder::Quadrupole` or higher.
+    pub quadrupole: Quadrupole<F>,
+    /// Traceless, about `center_of_mass`. Zero unless built with `ExpansionOrder::Octupole`.
+    pub octupole: Octupole<F>,
+    /// Indices into `Tree::bodies`, and `Tree::body_ids`.
+    pub body_range: Range<usize>,
 }
 
-impl fmt::Display for Node {
+impl<F: Float> Node<F> {
+    /// The positive and negative monopoles, as (mass, center) pairs, omitting empty ones.
+    pub fn monopoles(&self) -> impl Iterator<Item = (F, F::Vec3)> {
+        [
+            (self.mass_pos, self.center_pos),
+            (self.mass_neg, self.center_neg),
+        ]
+        .into_iter()
+        .filter(|(mass, _)| *mass != F::zero())
+    }
+
+    /// The dipole moment about `center_of_mass`. This is zero for nodes whose bodies have the same sign,
+    /// but not for mixed-sign nodes, e.g. of charges; multipole expansions about `center_of_mass` must include it.
+    pub fn dipole_about_center_of_mass(&self) -> F::Vec3 {
+        if self.mass_pos == F::zero() || self.mass_neg == F::zero() {
+            return F::Vec3::new_zero();
+        }
+        self.dipole + (self.bounding_box.center - self.center_of_mass) * self.mass
+    }
+}
+
+impl<F: Float> fmt::Display for Node<F> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -162,97 +300,296 @@ impl fmt::Display for Node {
     }
 }
 
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A source body's position and mass, as captured when building the tree. We use these to sum
+/// leaf contributions body-by-body, instead of using the leaf's center of mass.
+pub struct SourceBody<F: Float = f64> {
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serde_vec3::serialize::<F, _>",
+            deserialize_with = "serde_vec3::deserialize::<F, _>"
+        )
+    )]
+    pub posit: F::Vec3,
+    pub mass: F,
+}
+
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// A recursive tree. Each node can be subdivided  Terminates with `NodeType::NodeTerminal`.
-pub struct Tree {
+pub struct Tree<F: Float = f64> {
     /// Order matters; we index this by `Node::children`.
     // Note: It doesn't appear that passing in a persistent, pre-allocated nodes Vec from the applicatoni
     // has a significant impact on tree construction time.
-    pub nodes: Vec<Node>,
+    pub nodes: Vec<Node<F>>,
+    /// Bodies, in tree order: Each node's bodies are the contiguous range `Node::body_range`.
+    pub bodies: Vec<SourceBody<F>>,
+    /// The permutation from tree order to body id, i.e. the index in the body array used to make
+    /// the tree. `bodies[i]` corresponds to the body with id `body_ids[i]`.
+    pub body_ids: Vec<usize>,
+    /// The inverse of `body_ids`: The index in `bodies` of the body with each id.
+    pub body_indices: Vec<usize>,
+    /// Indices of nodes that reached `BhConfig::max_tree_depth` with more than `max_bodies_per_node`
+    /// bodies. These are leaves, instead of being subdivided further. If non-empty, this usually
+    /// indicates clustered, or duplicate body positions.
+    pub depth_limited: Vec<usize>,
 }
 
-impl Tree {
+impl<F: Float> Tree<F> {
     /// Constructs a tree. Call this externaly using all bodies, once per time step.
     /// It creates the entire tree, branching until each cell has `MAX_BODIES_PER_NODE` or fewer
-    /// bodies, or it reaches a maximum recursion depth.
+    /// bodies, or it reaches a maximum recursion depth. Nodes at the maximum depth become leaves, and
+    /// are recorded in `depth_limited`.
     ///
     /// We partially transverse it as-required while calculating the force on a given target.
-    pub fn new<T: BodyModel>(bodies: &[T], bb: &Cube, config: &BhConfig) -> Self {
-        // Convert &[T] to &[&T].
-        let body_refs: Vec<&T> = bodies.iter().collect();
+    pub fn new<T: BodyModel<F>>(bodies: &[T], bb: &Cube<F>, config: &BhConfig<F>) -> Self {
+        // We partition these in place as we build the tree, so each node's bodies are contiguous.
+        let mut bodies_sorted: Vec<SourceBody<F>> = bodies
+            .iter()
+            .map(|b| SourceBody {
+                posit: b.posit(),
+                mass: b.mass(),
+            })
+            .collect();
 
-        // todo: Refine this guess A/R.
-        // From an unrigorous benchmark, preallocating seems to be slightly faster, but not significantly so?
-        let mut nodes = Vec::with_capacity(bodies.len() * 7 / 4);
+        // body ids matches indexes with bodies. We permute this along with `bodies_sorted`.
+        let mut body_ids: Vec<usize> = (0..bodies.len()).collect();
 
-        let mut current_node_i: usize = 0;
+        let (nodes, depth_limited) =
+            build_subtree(&mut bodies_sorted, &mut body_ids, 0, bb, 0, config);
 
-        // Stack to simulate recursion: Each entry contains (bodies, bounding box, parent_id, child_index, depth).
-        let mut stack = Vec::new();
+        Self {
+            nodes,
+            bodies: bodies_sorted,
+            body_indices: invert_permutation(&body_ids),
+            body_ids,
+            depth_limited,
+        }
+    }
 
-        // body ids matches indexes with bodies.
-        let body_ids_init: Vec<usize> = body_refs.iter().enumerate().map(|(id, _)| id).collect();
+    /// Constructs a tree, as with `new`, splitting the top levels of the octree across rayon tasks.
+    /// This is faster for large body counts. The node order differs from `new`, but the tree is
+    /// otherwise equivalent.
+    pub fn new_par<T: BodyModel<F> + Sync>(
+        bodies: &[T],
+        bb: &Cube<F>,
+        config: &BhConfig<F>,
+    ) -> Self {
+        let mut bodies_sorted: Vec<SourceBody<F>> = bodies
+            .par_iter()
+            .map(|b| SourceBody {
+                posit: b.posit(),
+                mass: b.mass(),
+            })
+            .collect();
 
-        stack.push((body_refs.to_vec(), body_ids_init, bb.clone(), None, 0));
+        let mut body_ids: Vec<usize> = (0..bodies.len()).collect();
 
-        while let Some((bodies_, body_ids, bb_, parent_id, depth)) = stack.pop() {
-            if depth > config.max_tree_depth {
-                break;
-            }
-            let (center_of_mass, mass) = center_of_mass(&bodies_);
-
-            let node_id = current_node_i;
-            nodes.push(Node {
-                id: node_id,
-                bounding_box: bb_.clone(),
-                mass,
-                center_of_mass,
-                children: Vec::new(),
-                body_ids: body_ids.clone(), // todo: The clone...
-            });
-
-            current_node_i += 1;
-
-            if let Some(pid) = parent_id {
-                // Rust is requesting an explicit type here.
-                let n: &mut Node = &mut nodes[pid];
-                n.children.push(node_id);
-            }
+        let (nodes, depth_limited) =
+            build_subtree_par(&mut bodies_sorted, &mut body_ids, 0, bb, 0, config);
 
-            // If multiple (past our threshold) bodies are in this node, create an internal node and push its ID.
-            // Divide into octants and partition bodies. Otherwise, create a leaf node.
-            if bodies_.len() > config.max_bodies_per_node {
-                let octants = bb_.divide_into_octants();
-                let bodies_by_octant = partition(&bodies_, &body_ids, &bb_);
-
-                // Add each octant with bodies to the stack.
-                for (i, octant) in octants.into_iter().enumerate() {
-                    if !bodies_by_octant[i].is_empty() {
-                        let mut bto = Vec::with_capacity(bodies_by_octant[i].len());
-                        let mut ids_this_octant = Vec::with_capacity(bodies_by_octant[i].len());
-
-                        // todo: The clone etc?
-                        for (body, id) in &bodies_by_octant[i] {
-                            bto.push(*body);
-                            ids_this_octant.push(*id);
-                        }
-
-                        stack.push((bto, ids_this_octant, octant, Some(node_id), depth + 1));
-                    }
-                }
-            }
+        Self {
+            nodes,
+            bodies: bodies_sorted,
+            body_indices: invert_permutation(&body_ids),
+            body_ids,
+            depth_limited,
+        }
+    }
+
+    /// The ids (indices in the body array used to make the tree) of bodies in a node, including
+    /// those of its sub-nodes.
+    pub fn node_body_ids(&self, node: &Node<F>) -> &[usize] {
+        &self.body_ids[node.body_range.clone()]
+    }
+
+    /// Positions and masses of bodies in a node, including those of its sub-nodes.
+    pub fn node_bodies(&self, node: &Node<F>) -> &[SourceBody<F>] {
+        &self.bodies[node.body_range.clone()]
+    }
+
+    /// If a node, including its sub-nodes, contains the body with id `id`. Returns false for ids not in
+    /// the tree, e.g. `usize::MAX`.
+    pub fn node_contains_body(&self, node: &Node<F>, id: usize) -> bool {
+        self.body_indices
+            .get(id)
+            .is_some_and(|i| node.body_range.contains(i))
+    }
+
+    /// If a node returned by `leaves` must be summed body-by-body, instead of using its aggregate
+    /// properties: Leaves, and (rarely, at high θ) grouped nodes containing the target.
+    pub(crate) fn sum_directly(&self, node: &Node<F>, id_target: usize) -> bool {
+        node.children.is_empty() || self.node_contains_body(node, id_target)
+    }
+
+    /// Calculate force on each target, as with `run_bh`, returning one result per target. This parallelizes
+    /// over targets, and sums sources for each target serially, avoiding nested parallelism.
+    ///
+    /// If `targets_are_sources`, target `i` is treated as body id `i`, preventing self-interaction; use this
+    /// when targets are the bodies used to build the tree. Otherwise, all sources act on each target.
+    pub fn run_bh_batch<T, K>(
+        &self,
+        targets: &[T],
+        targets_are_sources: bool,
+        config: &BhConfig<F>,
+        force_fn: &K,
+    ) -> Vec<F::Vec3>
+    where
+        T: BodyModel<F> + Sync,
+        K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+    {
+        self.run_bh_batch_with_acc_prev(targets, targets_are_sources, None, config, force_fn)
+    }
+
+    /// As `run_bh_batch`, with each target's acceleration magnitude from the previous step, divided by the
+    /// coupling constant, indexed as `targets`. Use this with `OpeningCriterion::RelativeForce`; see
+    /// `Tree::acc_prev_from` to compute these from previous results.
+    pub fn run_bh_batch_with_acc_prev<T, K>(
+        &self,
+        targets: &[T],
+        targets_are_sources: bool,
+        acc_prev: Option<&[F]>,
+        config: &BhConfig<F>,
+        force_fn: &K,
+    ) -> Vec<F::Vec3>
+    where
+        T: BodyModel<F> + Sync,
+        K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+    {
+        if let Some(a) = acc_prev {
+            assert_eq!(
+                a.len(),
+                targets.len(),
+                "`acc_prev` must have one value per target."
+            );
+        }
+
+        targets
+            .par_iter()
+            .enumerate()
+            .map(|(i, target)| {
+                let id_target = if targets_are_sources { i } else { usize::MAX };
+                let acc_prev = acc_prev.map(|a| a[i]);
+                self.force_serial(target.posit(), id_target, acc_prev, config, force_fn)
+            })
+            .collect()
+    }
+
+    /// Force on a single target, as with `run_bh`, summing nodes serially. Use this in loops that are
+    /// already parallelized over targets.
+    pub(crate) fn force_serial<K>(
+        &self,
+        posit_target: F::Vec3,
+        id_target: usize,
+        acc_prev: Option<F>,
+        config: &BhConfig<F>,
+        force_fn: &K,
+    ) -> F::Vec3
+    where
+        K: Fn(F::Vec3, F, F) -> F::Vec3,
+    {
+        let mut result = F::Vec3::new_zero();
+        for leaf in self.leaves_with_acc_prev(posit_target, acc_prev, config) {
+            result += self.force_from_node(leaf, posit_target, id_target, config, force_fn);
+        }
+        result
+    }
+
+    /// Force from a node returned by `leaves`. A leaf, or (rarely, at high θ) a grouped node containing
+    /// the target, is summed body-by-body. Other nodes use their center of mass.
+    pub(crate) fn force_from_node<K>(
+        &self,
+        node: &Node<F>,
+        posit_target: F::Vec3,
+        id_target: usize,
+        config: &BhConfig<F>,
+        force_fn: &K,
+    ) -> F::Vec3
+    where
+        K: Fn(F::Vec3, F, F) -> F::Vec3,
+    {
+        if self.sum_directly(node, id_target) {
+            return self.sum_bodies(node, posit_target, id_target, config, force_fn);
         }
 
-        // Now that nodes are populated, rearrange so index == `id`. We will then index by `children`.
-        nodes.sort_by(|l, r| l.id.partial_cmp(&r.id).unwrap());
+        // Positive and negative sources use separate centers, so mixed-sign nodes, e.g. of charges,
+        // are handled correctly.
+        let mut result = F::Vec3::new_zero();
+        for (mass, center) in node.monopoles() {
+            let acc_diff = self.displacement(center, posit_target, config);
+            result += force_from_src(acc_diff, mass, force_fn);
+        }
+        result
+    }
+
+    /// Sum the force function over each body in a node directly, excluding the target.
+    pub(crate) fn sum_bodies<K>(
+        &self,
+        node: &Node<F>,
+        posit_target: F::Vec3,
+        id_target: usize,
+        config: &BhConfig<F>,
+        force_fn: &K,
+    ) -> F::Vec3
+    where
+        K: Fn(F::Vec3, F, F) -> F::Vec3,
+    {
+        let mut result = F::Vec3::new_zero();
+
+        for body in self.bodies_excluding(node, id_target) {
+            let acc_diff = self.displacement(body.posit, posit_target, config);
+            result += force_from_src(acc_diff, body.mass, force_fn);
+        }
+
+        result
+    }
+
+    /// The vector from target to source. If periodic, this uses the minimum image, with the root
+    /// node's bounding box as the periodic cell.
+    pub(crate) fn displacement(
+        &self,
+        posit_src: F::Vec3,
+        posit_target: F::Vec3,
+        config: &BhConfig<F>,
+    ) -> F::Vec3 {
+        let diff = posit_src - posit_target;
+
+        if config.periodic && !self.nodes.is_empty() {
+            ewald::min_image(diff, self.nodes[0].bounding_box.width)
+        } else {
+            diff
+        }
+    }
 
-        Self { nodes }
+    /// Bodies in a node, excluding the target. This prevents self-interaction.
+    pub(crate) fn bodies_excluding(
+        &self,
+        node: &Node<F>,
+        id_target: usize,
+    ) -> impl Iterator<Item = &SourceBody<F>> {
+        node.body_range
+            .clone()
+            .filter(move |&i| self.body_ids[i] != id_target)
+            .map(|i| &self.bodies[i])
     }
 
     /// Get all leaves relevant to a given target. We use this to create a coarser
     /// version of the tree, containing only the nodes we need to calculate acceleration
     /// on a specific target.
-    pub fn leaves(&self, posit_target: Vec3, config: &BhConfig) -> Vec<&Node> {
+    pub fn leaves(&self, posit_target: F::Vec3, config: &BhConfig<F>) -> Vec<&Node<F>> {
+        self.leaves_with_acc_prev(posit_target, None, config)
+    }
+
+    /// As `leaves`, with the target's acceleration from the previous step, divided by the coupling
+    /// constant. This is used by `OpeningCriterion::RelativeForce`, and ignored by other criteria.
+    pub fn leaves_with_acc_prev(
+        &self,
+        posit_target: F::Vec3,
+        acc_prev: Option<F>,
+        config: &BhConfig<F>,
+    ) -> Vec<&Node<F>> {
         let mut result = Vec::new();
 
         if self.nodes.is_empty() {
@@ -267,14 +604,12 @@ impl Tree {
         while let Some(current_node_i) = stack.pop() {
             let node = &self.nodes[current_node_i];
 
-            if node.children.len() <= config.max_bodies_per_node {
+            if node.children.is_empty() {
                 result.push(node);
                 continue;
             }
 
-            let dist = (posit_target - node.center_of_mass).magnitude();
-
-            if node.bounding_box.width / dist < config.θ {
+            if self.accept_node(node, posit_target, acc_prev, config) {
                 result.push(node);
             } else {
                 // The source is near; add children to the stack to go deeper.
@@ -288,44 +623,314 @@ impl Tree {
     }
 }
 
-/// Compute center of mass as a position, and mass value.
-fn center_of_mass<T: BodyModel>(bodies: &[&T]) -> (Vec3, f64) {
-    let mut mass = 0.;
-    let mut center_of_mass = Vec3::new_zero();
+/// Below this many bodies, `new_par` builds a subtree on a single thread. It's lower in tests, so they cover
+/// the parallel path without large body counts.
+const PAR_BUILD_MIN_BODIES: usize = if cfg!(test) { 64 } else { 20_000 };
+
+/// Create a node; its children are added later.
+fn make_node<F: Float>(
+    id: usize,
+    bodies: &[SourceBody<F>],
+    bb: &Cube<F>,
+    body_range: Range<usize>,
+    config: &BhConfig<F>,
+) -> Node<F> {
+    let agg = aggregate(bodies.iter().map(|b| (b.mass, b.posit)));
+    let (quadrupole, octupole) =
+        multipole::moments(bodies, agg.center_of_mass, config.expansion_order);
 
-    for body in bodies {
-        mass += body.mass();
-        center_of_mass += body.posit() * body.mass();
+    Node {
+        id,
+        bounding_box: bb.clone(),
+        mass: agg.mass,
+        center_of_mass: agg.center_of_mass,
+        mass_pos: agg.mass_pos,
+        center_pos: agg.center_pos,
+        mass_neg: agg.mass_neg,
+        center_neg: agg.center_neg,
+        // Set by `set_dipoles`, once children are known.
+        dipole: F::Vec3::new_zero(),
+        quadrupole,
+        octupole,
+        children: Vec::new(),
+        body_range,
     }
+}
+
+/// Build a tree, or subtree, partitioning `bodies` and `body_ids` in place. Node body ranges are offset
+/// by `offset`, so they index into the full arrays when building a subtree. Returns the nodes, with
+/// the subtree's root first, and the indices of depth-limited nodes.
+fn build_subtree<F: Float>(
+    bodies: &mut [SourceBody<F>],
+    body_ids: &mut [usize],
+    offset: usize,
+    bb: &Cube<F>,
+    depth_init: usize,
+    config: &BhConfig<F>,
+) -> (Vec<Node<F>>, Vec<usize>) {
+    let mut scratch = Vec::with_capacity(bodies.len());
+
+    // todo: Refine this guess A/R.
+    // From an unrigorous benchmark, preallocating seems to be slightly faster, but not significantly so?
+    let mut nodes = Vec::with_capacity(bodies.len() * 7 / 4);
+
+    let mut current_node_i: usize = 0;
+    let mut depth_limited = Vec::new();
+
+    // Stack to simulate recursion: Each entry contains (body range, bounding box, parent_id, depth).
+    let mut stack = Vec::new();
+
+    stack.push((0..bodies.len(), bb.clone(), None, depth_init));
+
+    while let Some((body_range, bb_, parent_id, depth)) = stack.pop() {
+        let node_id = current_node_i;
+        nodes.push(make_node(
+            node_id,
+            &bodies[body_range.clone()],
+            &bb_,
+            offset + body_range.start..offset + body_range.end,
+            config,
+        ));
 
-    if mass.abs() > f64::EPSILON {
-        center_of_mass /= mass;
+        current_node_i += 1;
+
+        if let Some(pid) = parent_id {
+            // Rust is requesting an explicit type here.
+            let n: &mut Node<F> = &mut nodes[pid];
+            n.children.push(node_id);
+        }
+
+        // If multiple (past our threshold) bodies are in this node, create an internal node and push its ID.
+        // Divide into octants and partition bodies. Otherwise, create a leaf node.
+        if body_range.len() > config.max_bodies_per_node {
+            if depth >= config.max_tree_depth {
+                // Leave this as an oversized leaf; continue building other branches.
+                depth_limited.push(node_id);
+                continue;
+            }
+
+            let octants = bb_.divide_into_octants();
+            let ranges_by_octant = partition(
+                &mut bodies[body_range.clone()],
+                &mut body_ids[body_range.clone()],
+                &mut scratch,
+                &bb_,
+            );
+
+            // Add each octant with bodies to the stack.
+            for (i, octant) in octants.into_iter().enumerate() {
+                let r = &ranges_by_octant[i];
+                if !r.is_empty() {
+                    let range_this_octant = body_range.start + r.start..body_range.start + r.end;
+                    stack.push((range_this_octant, octant, Some(node_id), depth + 1));
+                }
+            }
+        }
     }
 
-    (center_of_mass, mass)
+    // Now that nodes are populated, rearrange so index == `id`. We will then index by `children`.
+    nodes.sort_by(|l, r| l.id.partial_cmp(&r.id).unwrap());
+
+    set_dipoles(&mut nodes, bodies, offset);
+
+    (nodes, depth_limited)
+}
+
+/// Compute dipoles bottom-up: Leaves from their bodies, and other nodes by combining their children.
+/// `bodies` starts at index `offset` of the tree's bodies. Children must follow their parents.
+fn set_dipoles<F: Float>(nodes: &mut [Node<F>], bodies: &[SourceBody<F>], offset: usize) {
+    for i in (0..nodes.len()).rev() {
+        let node = &nodes[i];
+        let center = node.bounding_box.center;
+
+        let dipole = if node.children.is_empty() {
+            let range = node.body_range.start - offset..node.body_range.end - offset;
+            multipole::dipole(&bodies[range], center)
+        } else {
+            multipole::dipole_from_children(node.children.iter().map(|&c| &nodes[c]), center)
+        };
+
+        nodes[i].dipole = dipole;
+    }
 }
 
-/// Partition bodies into each of the 8 octants.
-fn partition<'a, T: BodyModel>(
-    bodies: &[&'a T],
-    body_ids: &[usize],
-    bb: &Cube,
-) -> [Vec<(&'a T, usize)>; 8] {
-    let mut result: [Vec<(&'a T, usize)>; 8] = Default::default();
-
-    for (i, body) in bodies.iter().enumerate() {
-        let mut index = 0;
-        if body.posit().x > bb.center.x {
-            index |= 0b001;
+/// As `build_subtree`, but builds each octant's subtree in parallel, while the node is large enough to
+/// benefit. We then merge the subtrees, offsetting their node indices.
+fn build_subtree_par<F: Float>(
+    bodies: &mut [SourceBody<F>],
+    body_ids: &mut [usize],
+    offset: usize,
+    bb: &Cube<F>,
+    depth: usize,
+    config: &BhConfig<F>,
+) -> (Vec<Node<F>>, Vec<usize>) {
+    if bodies.len() < PAR_BUILD_MIN_BODIES
+        || bodies.len() <= config.max_bodies_per_node
+        || depth >= config.max_tree_depth
+    {
+        return build_subtree(bodies, body_ids, offset, bb, depth, config);
+    }
+
+    let root = make_node(0, bodies, bb, offset..offset + bodies.len(), config);
+
+    let octants = bb.divide_into_octants();
+    let ranges_by_octant = partition(bodies, body_ids, &mut Vec::with_capacity(bodies.len()), bb);
+
+    // Octant ranges are contiguous, and in order; split the slices into one for each.
+    let mut bodies_rem = bodies;
+    let mut ids_rem = body_ids;
+    let mut octant_data = Vec::with_capacity(8);
+
+    for (i, octant) in octants.into_iter().enumerate() {
+        let r = &ranges_by_octant[i];
+        let (bodies_oct, b) = std::mem::take(&mut bodies_rem).split_at_mut(r.len());
+        let (ids_oct, ids) = std::mem::take(&mut ids_rem).split_at_mut(r.len());
+        bodies_rem = b;
+        ids_rem = ids;
+
+        if !r.is_empty() {
+            octant_data.push((bodies_oct, ids_oct, offset + r.start, octant));
         }
-        if body.posit().y > bb.center.y {
-            index |= 0b010;
+    }
+
+    let subtrees: Vec<_> = octant_data
+        .into_par_iter()
+        .map(|(bodies_oct, ids_oct, offset_oct, octant)| {
+            build_subtree_par(bodies_oct, ids_oct, offset_oct, &octant, depth + 1, config)
+        })
+        .collect();
+
+    let mut nodes = vec![root];
+    let mut depth_limited = Vec::new();
+
+    for (sub_nodes, sub_depth_limited) in subtrees {
+        let base = nodes.len();
+        nodes[0].children.push(base);
+
+        depth_limited.extend(sub_depth_limited.into_iter().map(|i| i + base));
+
+        for mut node in sub_nodes {
+            node.id += base;
+            for child in &mut node.children {
+                *child += base;
+            }
+            nodes.push(node);
+        }
+    }
+
+    // Subtree dipoles are complete; combine them for the root.
+    nodes[0].dipole = multipole::dipole_from_children(
+        nodes[0].children.iter().map(|&c| &nodes[c]),
+        nodes[0].bounding_box.center,
+    );
+
+    (nodes, depth_limited)
+}
+
+/// The inverse of a permutation: `result[perm[i]] == i`.
+pub(crate) fn invert_permutation(perm: &[usize]) -> Vec<usize> {
+    let mut result = vec![0; perm.len()];
+    for (i, &p) in perm.iter().enumerate() {
+        result[p] = i;
+    }
+    result
+}
+
+/// Mass (or charge) aggregates of a node. See the fields of `Node`.
+pub(crate) struct Aggregate<F: Float> {
+    pub mass: F,
+    pub center_of_mass: F::Vec3,
+    pub mass_pos: F,
+    pub center_pos: F::Vec3,
+    pub mass_neg: F,
+    pub center_neg: F::Vec3,
+}
+
+/// Aggregate sources, as (mass, position) pairs. These may be bodies, or the positive and negative
+/// monopoles of child nodes.
+pub(crate) fn aggregate<F: Float>(sources: impl Iterator<Item = (F, F::Vec3)>) -> Aggregate<F> {
+    let mut mass_pos = F::zero();
+    let mut mass_neg = F::zero();
+    let mut weighted_pos = F::Vec3::new_zero();
+    let mut weighted_neg = F::Vec3::new_zero();
+
+    for (mass, posit) in sources {
+        if mass > F::zero() {
+            mass_pos += mass;
+            weighted_pos += posit * mass;
+        } else if mass < F::zero() {
+            mass_neg += mass;
+            weighted_neg += posit * mass;
         }
-        if body.posit().z > bb.center.z {
-            index |= 0b100;
+    }
+
+    let mass_abs = mass_pos - mass_neg;
+    let center_of_mass = if mass_abs > F::zero() {
+        (weighted_pos - weighted_neg) / mass_abs
+    } else {
+        F::Vec3::new_zero()
+    };
+
+    let center = |weighted: F::Vec3, mass: F| {
+        if mass != F::zero() {
+            weighted / mass
+        } else {
+            center_of_mass
         }
+    };
+
+    Aggregate {
+        mass: mass_pos + mass_neg,
+        center_of_mass,
+        mass_pos,
+        center_pos: center(weighted_pos, mass_pos),
+        mass_neg,
+        center_neg: center(weighted_neg, mass_neg),
+    }
+}
+
+/// The octant a position is in, using the index order of `Cube::divide_into_octants`.
+fn octant_index<F: Float>(posit: F::Vec3, bb: &Cube<F>) -> usize {
+    let mut index = 0;
+    if posit.x() > bb.center.x() {
+        index |= 0b001;
+    }
+    if posit.y() > bb.center.y() {
+        index |= 0b010;
+    }
+    if posit.z() > bb.center.z() {
+        index |= 0b100;
+    }
+    index
+}
+
+/// Partition bodies, and their ids, in place into each of the 8 octants. Returns the range of each
+/// octant, relative to the start of the slices passed.
+fn partition<F: Float>(
+    bodies: &mut [SourceBody<F>],
+    body_ids: &mut [usize],
+    scratch: &mut Vec<(SourceBody<F>, usize)>,
+    bb: &Cube<F>,
+) -> [Range<usize>; 8] {
+    let mut counts = [0; 8];
+    for body in bodies.iter() {
+        counts[octant_index(body.posit, bb)] += 1;
+    }
 
-        result[index].push((body, body_ids[i]));
+    let mut next = [0; 8];
+    for i in 1..8 {
+        next[i] = next[i - 1] + counts[i - 1];
+    }
+    let result = std::array::from_fn(|i| next[i]..next[i] + counts[i]);
+
+    scratch.clear();
+    scratch.extend(bodies.iter().cloned().zip(body_ids.iter().copied()));
+
+    for (body, id) in scratch.drain(..) {
+        let i = octant_index(body.posit, bb);
+        bodies[next[i]] = body;
+        body_ids[next[i]] = id;
+        next[i] += 1;
     }
 
     result
@@ -336,31 +941,172 @@ fn partition<'a, T: BodyModel>(
 /// `id_target` is the index in the body array used to make the tree; it prevents self-interaction.
 /// Note that `mass` can be interchanged with `charge`, or similar.
 ///
+/// Leaf nodes are summed body-by-body, using each body's position and mass. Other nodes use their
+/// center of mass.
+///
 /// When handling target mass or charge, reflect that in your `force_fn`; not here.
-pub fn run_bh<F>(
-    posit_target: Vec3,
+pub fn run_bh<F, K>(
+    posit_target: F::Vec3,
+    id_target: usize,
+    tree: &Tree<F>,
+    config: &BhConfig<F>,
+    force_fn: &K,
+) -> F::Vec3
+where
+    F: Float,
+    K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+{
+    run_bh_with_acc_prev(posit_target, id_target, None, tree, config, force_fn)
+}
+
+/// As `run_bh`, with the target's acceleration magnitude from the previous step, divided by the coupling
+/// constant (e.g. G). Use this with `OpeningCriterion::RelativeForce`.
+pub fn run_bh_with_acc_prev<F, K>(
+    posit_target: F::Vec3,
     id_target: usize,
-    tree: &Tree,
-    config: &BhConfig,
-    force_fn: &F,
-) -> Vec3
+    acc_prev: Option<F>,
+    tree: &Tree<F>,
+    config: &BhConfig<F>,
+    force_fn: &K,
+) -> F::Vec3
 where
-    F: Fn(Vec3, f64, f64) -> Vec3 + Send + Sync,
+    F: Float,
+    K: Fn(F::Vec3, F, F) -> F::Vec3 + Send + Sync,
+{
+    tree.leaves_with_acc_prev(posit_target, acc_prev, config)
+        .par_iter()
+        .map(|leaf| tree.force_from_node(leaf, posit_target, id_target, config, force_fn))
+        .reduce(F::Vec3::new_zero, |acc, elem| acc + elem)
+}
+
+/// Calculate a scalar potential using the Barnes Hut algorithm, e.g. for energy diagnostics. This uses the same
+/// traversal as `run_bh`. The potential function passed as a parameter has signature
+/// `(mass_src: f64, distance: f64) -> f64`. For example, `|m, r| -G * m / r` for gravity.
+///
+/// When handling target mass or charge, reflect that in your `potential_fn`; not here. As with force,
+/// sources at the target's position, e.g. duplicate bodies, contribute nothing.
+pub fn run_bh_potential<F, P>(
+    posit_target: F::Vec3,
+    id_target: usize,
+    tree: &Tree<F>,
+    config: &BhConfig<F>,
+    potential_fn: &P,
+) -> F
+where
+    F: Float,
+    P: Fn(F, F) -> F + Send + Sync,
 {
     tree.leaves(posit_target, config)
         .par_iter()
-        .filter_map(|leaf| {
-            if leaf.body_ids.contains(&id_target) {
-                // Prevent self-interaction.
-                return None;
+        .map(|leaf| {
+            if tree.sum_directly(leaf, id_target) {
+                return tree
+                    .bodies_excluding(leaf, id_target)
+                    .map(|body| {
+                        let dist = tree
+                            .displacement(body.posit, posit_target, config)
+                            .magnitude();
+                        potential_from_src(body.mass, dist, potential_fn)
+                    })
+                    .sum();
             }
 
-            let acc_diff = leaf.center_of_mass - posit_target;
-            let dist = acc_diff.magnitude();
+            leaf.monopoles()
+                .map(|(mass, center)| {
+                    let dist = tree.displacement(center, posit_target, config).magnitude();
+                    potential_from_src(mass, dist, potential_fn)
+                })
+                .sum()
+        })
+        .sum()
+}
 
-            let acc_dir = acc_diff / dist; // Unit vec
+/// Apply the potential function for a single source, skipping it if at the target's position, as in
+/// `force_from_src`.
+fn potential_from_src<F, P>(mass_src: F, dist: F, potential_fn: &P) -> F
+where
+    F: Float,
+    P: Fn(F, F) -> F,
+{
+    if dist == F::zero() {
+        return F::zero();
+    }
 
-            Some(force_fn(acc_dir, leaf.mass, dist))
-        })
-        .reduce(Vec3::new_zero, |acc, elem| acc + elem)
+    potential_fn(mass_src, dist)
+}
+
+/// Apply the force function for a single source, either a body, or a node's center of mass. `acc_diff`
+/// is the vector from the target to the source.
+///
+/// A source at the target's position, e.g. a duplicate body, has no direction; it contributes nothing,
+/// as for any central force by symmetry. This avoids NaNs, including with softened kernels.
+pub(crate) fn force_from_src<F, K>(acc_diff: F::Vec3, mass_src: F, force_fn: &K) -> F::Vec3
+where
+    F: Float,
+    K: Fn(F::Vec3, F, F) -> F::Vec3,
+{
+    let dist = acc_diff.magnitude();
+    if dist == F::zero() {
+        return F::Vec3::new_zero();
+    }
+
+    let acc_dir = acc_diff / dist; // Unit vec
+
+    force_fn(acc_dir, mass_src, dist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn new_par_matches_new() {
+        let config = BhConfig {
+            max_bodies_per_node: 2,
+            ..Default::default()
+        };
+
+        let bodies = test_utils::bodies(2_000);
+        let bb = Cube::from_bodies(&bodies, 0.01, false).unwrap();
+        let tree = Tree::new(&bodies, &bb, &config);
+        let tree_par = Tree::new_par(&bodies, &bb, &config);
+
+        assert_eq!(tree_par.nodes.len(), tree.nodes.len());
+        for (i, &id) in tree_par.body_ids.iter().enumerate() {
+            assert_eq!(tree_par.body_indices[id], i);
+        }
+
+        let (root, root_par) = (&tree.nodes[0], &tree_par.nodes[0]);
+        assert!((root_par.dipole - root.dipole).magnitude() < 1e-12 * root.dipole.magnitude());
+        assert!((root_par.mass - root.mass).abs() < 1e-12 * root.mass.abs());
+
+        let force_fn = |acc_dir, mass_src, dist: f64| acc_dir * (mass_src / dist.powi(2));
+        let accel = tree.run_bh_batch(&bodies, true, &config, &force_fn);
+        let accel_par = tree_par.run_bh_batch(&bodies, true, &config, &force_fn);
+
+        for (i, (a, a_par)) in accel.iter().zip(&accel_par).enumerate() {
+            assert!(
+                (*a_par - *a).magnitude() < 1e-12 * a.magnitude(),
+                "Body {i}: new: {a:?}, new_par: {a_par:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn tree_serde_round_trip() {
+        let tree = test_utils::tree();
+        let decoded: Tree = serde_json::from_str(&serde_json::to_string(&tree).unwrap()).unwrap();
+        test_utils::assert_trees_eq(&decoded, &tree);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_serde_round_trip() {
+        let config = test_utils::config();
+        let decoded: BhConfig =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        test_utils::assert_configs_eq(&decoded, &config);
+    }
 }
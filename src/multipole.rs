@@ -0,0 +1,235 @@
+//! Higher-order multipole moments for tree nodes, and an evaluation function that applies them.
+//! These corrections assume a 1/r potential, e.g. Newtonian gravity, or Coulomb force.
+//!
+//! Tensors are traceless, and taken about the node's center of mass. Since they're symmetric,
+//! we store only their unique components. For mixed-sign nodes, e.g. of charges, the center of mass is
+//! that of `|mass|`, so expansions about it also include the dipole term.
+
+#[cfg(feature = "encode")]
+use bincode::{Decode, Encode};
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{BhConfig, Float, Node, SourceBody, Tree, Vector};
+
+/// Components xx, xy, xz, yy, yz, zz.
+pub type Quadrupole<F = f64> = [F; 6];
+/// Components xxx, xxy, xxz, xyy, xyz, xzz, yyy, yyz, yzz, zzz.
+pub type Octupole<F = f64> = [F; 10];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "encode", derive(Encode, Decode))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The highest-order term used to approximate grouped nodes. Higher orders allow a higher θ for a given
+/// accuracy, at the cost of tree-construction time and per-node evaluation cost. Only `run_bh_multipole`
+/// uses terms above the monopole.
+pub enum ExpansionOrder {
+    #[default]
+    Monopole,
+    Quadrupole,
+    Octupole,
+}
+
+/// The dipole moment about `center`. For charges, this is the leading term for neutral nodes.
+pub(crate) fn dipole<F: Float>(bodies: &[SourceBody<F>], center: F::Vec3) -> F::Vec3 {
+    let mut result = F::Vec3::new_zero();
+    for body in bodies {
+        result += (body.posit - center) * body.mass;
+    }
+    result
+}
+
+/// The dipole moment about `center`, combined from child nodes' dipoles (each about its own bounding box
+/// center), and net masses. This avoids revisiting each body at every level of the tree.
+pub(crate) fn dipole_from_children<'a, F: Float + 'a>(
+    children: impl Iterator<Item = &'a Node<F>>,
+    center: F::Vec3,
+) -> F::Vec3 {
+    let mut result = F::Vec3::new_zero();
+    for child in children {
+        result += child.dipole + (child.bounding_box.center - center) * child.mass;
+    }
+    result
+}
+
+/// Compute quadrupole and octupole moments about the center of mass. Terms above `order` are left at 0.
+pub(crate) fn moments<F: Float>(
+    bodies: &[SourceBody<F>],
+    center_of_mass: F::Vec3,
+    order: ExpansionOrder,
+) -> (Quadrupole<F>, Octupole<F>) {
+    let mut quad = [F::zero(); 6];
+    let mut oct = [F::zero(); 10];
+
+    if order == ExpansionOrder::Monopole {
+        return (quad, oct);
+    }
+
+    let c3 = F::from_f64(3.);
+    let c9 = F::from_f64(9.);
+    let c15 = F::from_f64(15.);
+
+    for body in bodies {
+        let m = body.mass;
+        let d = body.posit - center_of_mass;
+        let (x, y, z) = (d.x(), d.y(), d.z());
+        let d_sq = d.magnitude_squared();
+
+        quad[0] += m * (c3 * x * x - d_sq);
+        quad[1] += m * c3 * x * y;
+        quad[2] += m * c3 * x * z;
+        quad[3] += m * (c3 * y * y - d_sq);
+        quad[4] += m * c3 * y * z;
+        quad[5] += m * (c3 * z * z - d_sq);
+
+        if order == ExpansionOrder::Octupole {
+            oct[0] += m * (c15 * x.powi(3) - c9 * d_sq * x);
+            oct[1] += m * (c15 * x * x * y - c3 * d_sq * y);
+            oct[2] += m * (c15 * x * x * z - c3 * d_sq * z);
+            oct[3] += m * (c15 * x * y * y - c3 * d_sq * x);
+            oct[4] += m * c15 * x * y * z;
+            oct[5] += m * (c15 * x * z * z - c3 * d_sq * x);
+            oct[6] += m * (c15 * y.powi(3) - c9 * d_sq * y);
+            oct[7] += m * (c15 * y * y * z - c3 * d_sq * z);
+            oct[8] += m * (c15 * y * z * z - c3 * d_sq * y);
+            oct[9] += m * (c15 * z.powi(3) - c9 * d_sq * z);
+        }
+    }
+
+    (quad, oct)
+}
+
+/// The gradient of the 1/r potential from a grouped node, at `r` (target minus center of mass),
+/// up to `order`. The dipole term is always included; it's zero unless the node has mixed signs.
+fn field_expansion<F: Float>(
+    r: F::Vec3,
+    mass: F,
+    dipole: F::Vec3,
+    quad: &Quadrupole<F>,
+    oct: &Octupole<F>,
+    order: ExpansionOrder,
+) -> F::Vec3 {
+    let (x, y, z) = (r.x(), r.y(), r.z());
+    let r_sq = r.magnitude_squared();
+    let r_mag = r_sq.sqrt();
+    let r3 = r_sq * r_mag;
+
+    let r5 = r3 * r_sq;
+    let r7 = r5 * r_sq;
+
+    // Monopole: ∇(M / r), and dipole: ∇(p·r / r^3)
+    let mut result = r * (-mass / r3) + dipole / r3 - r * (F::from_f64(3.) * dipole.dot(r) / r5);
+
+    if order == ExpansionOrder::Monopole {
+        return result;
+    }
+
+    // Quadrupole: ∇(Q_ij r_i r_j / 2r^5)
+    let [xx, xy, xz, yy, yz, zz] = *quad;
+    let qr = F::Vec3::new(
+        xx * x + xy * y + xz * z,
+        xy * x + yy * y + yz * z,
+        xz * x + yz * y + zz * z,
+    );
+    let q = qr.dot(r);
+
+    result += qr / r5 - r * (F::from_f64(2.5) * q / r7);
+
+    if order == ExpansionOrder::Quadrupole {
+        return result;
+    }
+
+    // Octupole: ∇(O_ijk r_i r_j r_k / 6r^7)
+    let [xxx, xxy, xxz, xyy, xyz, xzz, yyy, yyz, yzz, zzz] = *oct;
+    let two = F::from_f64(2.);
+    let (x2, y2, z2) = (x * x, y * y, z * z);
+    let (xy2, xz2, yz2) = (two * x * y, two * x * z, two * y * z);
+
+    let orr = F::Vec3::new(
+        xxx * x2 + xyy * y2 + xzz * z2 + xxy * xy2 + xxz * xz2 + xyz * yz2,
+        xxy * x2 + yyy * y2 + yzz * z2 + xyy * xy2 + xyz * xz2 + yyz * yz2,
+        xxz * x2 + yyz * y2 + zzz * z2 + xyz * xy2 + xzz * xz2 + yzz * yz2,
+    );
+    let o = orr.dot(r);
+
+    result += orr / (two * r7) - r * (F::from_f64(7.) * o / (F::from_f64(6.) * r7 * r_sq));
+
+    result
+}
+
+/// Calculate force using the Barnes Hut algorithm, applying quadrupole and octupole corrections
+/// to grouped nodes, as set by `BhConfig::expansion_order`. The tree must have been built with a config
+/// using the same, or a higher, order.
+///
+/// Unlike `run_bh`, this is specific to 1/r potentials: It returns `coupling * Σ mass_src * acc_dir / dist^2`,
+/// with `acc_dir` pointing from the target to the source. For example, `coupling` is G for gravitational
+/// acceleration, or `-k * charge_target` for Coulomb force. Sources may have mixed signs.
+pub fn run_bh_multipole<F: Float>(
+    posit_target: F::Vec3,
+    id_target: usize,
+    tree: &Tree<F>,
+    config: &BhConfig<F>,
+    coupling: F,
+) -> F::Vec3 {
+    let force_fn = |acc_dir: F::Vec3, mass_src: F, dist: F| acc_dir * (mass_src / dist.powi(2));
+
+    let result = tree
+        .leaves(posit_target, config)
+        .par_iter()
+        .map(|leaf| {
+            if tree.sum_directly(leaf, id_target) {
+                return tree.sum_bodies(leaf, posit_target, id_target, config, &force_fn);
+            }
+
+            field_expansion(
+                -tree.displacement(leaf.center_of_mass, posit_target, config),
+                leaf.mass,
+                leaf.dipole_about_center_of_mass(),
+                &leaf.quadrupole,
+                &leaf.octupole,
+                config.expansion_order,
+            )
+        })
+        .reduce(F::Vec3::new_zero, |acc, elem| acc + elem);
+
+    result * coupling
+}
+
+/// Calculate force using the Barnes Hut algorithm, applying the net monopole and dipole of grouped nodes,
+/// about their bounding box centers. This is for charges, where nodes are often nearly neutral, e.g. for
+/// molecular electrostatics, and the dipole is the leading term. It uses one evaluation per grouped node.
+/// `run_bh` also captures the dipole term, by applying positive and negative monopoles separately, and
+/// includes part of the quadrupole term; compare the two for your system using `run_direct`.
+///
+/// The result, and `coupling`, are as for [`run_bh_multipole`].
+pub fn run_bh_dipole<F: Float>(
+    posit_target: F::Vec3,
+    id_target: usize,
+    tree: &Tree<F>,
+    config: &BhConfig<F>,
+    coupling: F,
+) -> F::Vec3 {
+    let force_fn = |acc_dir: F::Vec3, mass_src: F, dist: F| acc_dir * (mass_src / dist.powi(2));
+
+    let result = tree
+        .leaves(posit_target, config)
+        .par_iter()
+        .map(|leaf| {
+            if tree.sum_directly(leaf, id_target) {
+                return tree.sum_bodies(leaf, posit_target, id_target, config, &force_fn);
+            }
+
+            // ∇(M / r + p·r / r^3), with r from the node's center to the target.
+            let r = -tree.displacement(leaf.bounding_box.center, posit_target, config);
+            let r_sq = r.magnitude_squared();
+            let r3 = r_sq * r_sq.sqrt();
+            let r5 = r3 * r_sq;
+
+            r * (-leaf.mass / r3) + leaf.dipole / r3
+                - r * (F::from_f64(3.) * leaf.dipole.dot(r) / r5)
+        })
+        .reduce(F::Vec3::new_zero, |acc, elem| acc + elem);
+
+    result * coupling
+}
@@ -0,0 +1,125 @@
+//! Criteria for deciding whether a node is far enough from a target to be grouped, or if we must
+//! open it and descend to its children. (The multipole acceptance criterion, or MAC)
+
+#[cfg(feature = "encode")]
+use bincode::{Decode, Encode};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{BhConfig, Float, Node, Tree, Vector};
+
+/// Grouping is disallowed if the target is within this multiple of a node's width from its center, in
+/// all dimensions. 0.6 allows a 10% margin past the node's edges. (From GADGET)
+const EDGE_SAFETY_RATIO: f64 = 0.6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "encode", derive(Encode, Decode))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// Selects how `Tree::leaves` decides to group a node. `dist` is from the target to the node's center of mass,
+/// and `width` is the node's bounding box width. `θ` from `BhConfig` is the tolerance for each.
+pub enum OpeningCriterion {
+    /// The classic Barnes Hut criterion: `width / dist < θ`. This can produce large errors when the center
+    /// of mass is near a node's corner, since the target can then be close to, or inside, the node.
+    #[default]
+    Geometric,
+    /// Salmon and Warren's criterion: `b_max / dist < θ`, where `b_max` is the distance from the center of
+    /// mass to the node's farthest corner. This accounts for off-center mass: At a given θ, it's stricter than
+    /// `Geometric` when the center of mass is far from the node's center, and looser otherwise, since `b_max` is
+    /// as low as `0.87 * width`.
+    BMax,
+    /// `Geometric`, but never groups a node if the target is inside it, or within 10% of its width
+    /// of its edges.
+    EdgeSafe,
+    /// GADGET's relative force criterion: `|mass| * width^2 / dist^4 < θ * acc_prev`, where `acc_prev` is the
+    /// magnitude of the target's acceleration from the previous step, divided by the coupling constant
+    /// (e.g. G). This bounds each node's force error relative to the total force. Typical θ values
+    /// are around 0.005. This includes the `EdgeSafe` check.
+    ///
+    /// The previous acceleration is passed via `Tree::leaves_with_acc_prev`, `run_bh_with_acc_prev`, or
+    /// `Tree::run_bh_batch_with_acc_prev`; without it, e.g. on the first step, this uses `EdgeSafe`, with
+    /// `BhConfig::θ_fallback` in place of `θ`.
+    RelativeForce,
+}
+
+impl<F: Float> Tree<F> {
+    /// If true, the node is far enough from the target to use its aggregate properties. Doesn't
+    /// handle leaves.
+    pub(crate) fn accept_node(
+        &self,
+        node: &Node<F>,
+        posit_target: F::Vec3,
+        acc_prev: Option<F>,
+        config: &BhConfig<F>,
+    ) -> bool {
+        let width = node.bounding_box.width;
+        let dist = self
+            .displacement(node.center_of_mass, posit_target, config)
+            .magnitude();
+
+        match config.opening_criterion {
+            OpeningCriterion::Geometric => width / dist < config.θ,
+            OpeningCriterion::BMax => {
+                let to_center = node.bounding_box.center - node.center_of_mass;
+                let half_width = width / F::from_f64(2.);
+
+                let b_max = F::Vec3::new(
+                    to_center.x().abs() + half_width,
+                    to_center.y().abs() + half_width,
+                    to_center.z().abs() + half_width,
+                )
+                .magnitude();
+
+                b_max / dist < config.θ
+            }
+            OpeningCriterion::EdgeSafe => {
+                width / dist < config.θ && !self.near_node(node, posit_target, config)
+            }
+            OpeningCriterion::RelativeForce => {
+                if self.near_node(node, posit_target, config) {
+                    return false;
+                }
+
+                match acc_prev {
+                    Some(acc) => {
+                        let mass_abs = node.mass_pos - node.mass_neg;
+                        mass_abs * width.powi(2) < config.θ * acc * dist.powi(4)
+                    }
+                    None => width / dist < config.θ_fallback,
+                }
+            }
+        }
+    }
+
+    /// Convert accelerations, e.g. results from the previous step, to `acc_prev` values for
+    /// `OpeningCriterion::RelativeForce`, by dividing their magnitudes by `force_fn`'s coupling constant.
+    /// We estimate it as `|force_fn(x̂, 1, w)| * w^2`, where `w` is the root node's width. This is exact for
+    /// 1/r^2 laws, and for softened ones if `w` is well beyond the softening length. Returns `None` if the
+    /// estimate isn't positive.
+    pub fn acc_prev_from<K>(&self, accel: &[F::Vec3], force_fn: &K) -> Option<Vec<F>>
+    where
+        K: Fn(F::Vec3, F, F) -> F::Vec3,
+    {
+        let coupling = self.coupling_estimate(force_fn)?;
+        Some(accel.iter().map(|a| a.magnitude() / coupling).collect())
+    }
+
+    /// `force_fn`'s coupling constant, as described in `acc_prev_from`.
+    pub(crate) fn coupling_estimate<K>(&self, force_fn: &K) -> Option<F>
+    where
+        K: Fn(F::Vec3, F, F) -> F::Vec3,
+    {
+        let width = self.nodes.first()?.bounding_box.width;
+        let dir = F::Vec3::new(F::one(), F::zero(), F::zero());
+
+        let result = force_fn(dir, F::one(), width).magnitude() * width.powi(2);
+        (result > F::zero() && result.is_finite()).then_some(result)
+    }
+
+    /// If the target is inside the node, or near its edges.
+    fn near_node(&self, node: &Node<F>, posit_target: F::Vec3, config: &BhConfig<F>) -> bool {
+        let to_center = self.displacement(node.bounding_box.center, posit_target, config);
+        let limit = node.bounding_box.width * F::from_f64(EDGE_SAFETY_RATIO);
+
+        to_center.x().abs() < limit && to_center.y().abs() < limit && to_center.z().abs() < limit
+    }
+}
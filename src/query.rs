@@ -0,0 +1,424 @@
+//! Spatial queries on the tree's bodies, e.g. for collision and contact checks. These reuse the tree
+//! built for force calculations, instead of requiring a second spatial index. Distances are Euclidean;
+//! they don't use the minimum image, even if `BhConfig::periodic` is set.
+//!
+//! Queries prune using `Node::bounding_box`. This requires each body to be in its nodes' bounding boxes,
+//! as is the case after `Tree::new`, or after `Tree::refit` with no escaped bodies.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use rayon::prelude::*;
+
+use crate::{BodyModel, Cube, Float, Tree, Vector};
+
+/// A node, or body, ordered by squared distance from the target. `index` is a node index, or a body id;
+/// ties are broken by it.
+struct Candidate<F> {
+    dist_sq: F,
+    index: usize,
+}
+
+impl<F: Float> Ord for Candidate<F> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist_sq
+            .partial_cmp(&other.dist_sq)
+            .unwrap_or(Ordering::Equal)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+impl<F: Float> PartialOrd for Candidate<F> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<F: Float> PartialEq for Candidate<F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<F: Float> Eq for Candidate<F> {}
+
+/// The squared distance from a point to the nearest point of a cube; 0 if the point is inside it.
+fn dist_sq_to_cube<F: Float>(cube: &Cube<F>, posit: F::Vec3) -> F {
+    let half = cube.width / F::from_f64(2.);
+    let d = posit - cube.center;
+
+    let outside = |v: F| (v.abs() - half).max(F::zero());
+    outside(d.x()).powi(2) + outside(d.y()).powi(2) + outside(d.z()).powi(2)
+}
+
+/// The squared distance from a point to the farthest corner of a cube.
+fn dist_sq_to_far_corner<F: Float>(cube: &Cube<F>, posit: F::Vec3) -> F {
+    let half = cube.width / F::from_f64(2.);
+    let d = posit - cube.center;
+
+    (d.x().abs() + half).powi(2) + (d.y().abs() + half).powi(2) + (d.z().abs() + half).powi(2)
+}
+
+impl<F: Float> Tree<F> {
+    /// Find all bodies within `radius` of `posit`, inclusive. Returns body ids (indices in the body array
+    /// used to make the tree), in no particular order.
+    pub fn within_radius(&self, posit: F::Vec3, radius: F) -> Vec<usize> {
+        let mut result = Vec::new();
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let radius_sq = radius.powi(2);
+
+        let mut stack = vec![0];
+        while let Some(node_i) = stack.pop() {
+            let node = &self.nodes[node_i];
+
+            if dist_sq_to_cube(&node.bounding_box, posit) > radius_sq {
+                continue;
+            }
+
+            // The whole node is in range; we don't need to check its bodies.
+            if dist_sq_to_far_corner(&node.bounding_box, posit) <= radius_sq {
+                result.extend_from_slice(self.node_body_ids(node));
+                continue;
+            }
+
+            if node.children.is_empty() {
+                for (body, &id) in self.node_bodies(node).iter().zip(self.node_body_ids(node)) {
+                    if (body.posit - posit).magnitude_squared() <= radius_sq {
+                        result.push(id);
+                    }
+                }
+            } else {
+                stack.extend_from_slice(&node.children);
+            }
+        }
+
+        result
+    }
+
+    /// Find all bodies in the axis-aligned box with corners `min` and `max`, inclusive. Returns body ids
+    /// (indices in the body array used to make the tree), in no particular order.
+    pub fn within_box(&self, min: F::Vec3, max: F::Vec3) -> Vec<usize> {
+        let mut result = Vec::new();
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let in_box = |p: F::Vec3| {
+            p.x() >= min.x()
+                && p.x() <= max.x()
+                && p.y() >= min.y()
+                && p.y() <= max.y()
+                && p.z() >= min.z()
+                && p.z() <= max.z()
+        };
+
+        let half = F::from_f64(0.5);
+
+        let mut stack = vec![0];
+        while let Some(node_i) = stack.pop() {
+            let node = &self.nodes[node_i];
+
+            let hw = node.bounding_box.width * half;
+            let node_min = node.bounding_box.center - F::Vec3::new(hw, hw, hw);
+            let node_max = node.bounding_box.center + F::Vec3::new(hw, hw, hw);
+
+            let overlaps = node_min.x() <= max.x()
+                && node_max.x() >= min.x()
+                && node_min.y() <= max.y()
+                && node_max.y() >= min.y()
+                && node_min.z() <= max.z()
+                && node_max.z() >= min.z();
+
+            if !overlaps {
+                continue;
+            }
+
+            // The whole node is in range; we don't need to check its bodies.
+            if in_box(node_min) && in_box(node_max) {
+                result.extend_from_slice(self.node_body_ids(node));
+                continue;
+            }
+
+            if node.children.is_empty() {
+                for (body, &id) in self.node_bodies(node).iter().zip(self.node_body_ids(node)) {
+                    if in_box(body.posit) {
+                        result.push(id);
+                    }
+                }
+            } else {
+                stack.extend_from_slice(&node.children);
+            }
+        }
+
+        result
+    }
+
+    /// Find the `k` bodies nearest to `posit`. Returns `(body id, distance)` pairs, sorted by distance;
+    /// ties are sorted by id. Returns fewer than `k` pairs if the tree has fewer bodies. `id_target` is
+    /// the id of the target, which is excluded from results; use `usize::MAX` if the target isn't a body
+    /// in the tree.
+    ///
+    /// This is a best-first search: We visit nodes in order of distance from the target, and stop once
+    /// the nearest unvisited node is farther than the `k`th nearest body found.
+    pub fn nearest_neighbors(&self, posit: F::Vec3, id_target: usize, k: usize) -> Vec<(usize, F)> {
+        if self.nodes.is_empty() || k == 0 {
+            return Vec::new();
+        }
+
+        // Nodes to visit, nearest first.
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse(Candidate {
+            dist_sq: dist_sq_to_cube(&self.nodes[0].bounding_box, posit),
+            index: 0,
+        }));
+
+        // The nearest bodies found so far, by id; farthest first.
+        let mut nearest: BinaryHeap<Candidate<F>> = BinaryHeap::with_capacity(k + 1);
+
+        while let Some(Reverse(node_cand)) = queue.pop() {
+            if nearest.len() == k
+                && let Some(farthest) = nearest.peek()
+                && node_cand.dist_sq > farthest.dist_sq
+            {
+                break;
+            }
+
+            let node = &self.nodes[node_cand.index];
+
+            if node.children.is_empty() {
+                for (body, &id) in self.node_bodies(node).iter().zip(self.node_body_ids(node)) {
+                    if id == id_target {
+                        continue;
+                    }
+
+                    let candidate = Candidate {
+                        dist_sq: (body.posit - posit).magnitude_squared(),
+                        index: id,
+                    };
+
+                    if nearest.len() < k {
+                        nearest.push(candidate);
+                    } else if let Some(farthest) = nearest.peek()
+                        && candidate < *farthest
+                    {
+                        nearest.pop();
+                        nearest.push(candidate);
+                    }
+                }
+            } else {
+                for &child_i in &node.children {
+                    queue.push(Reverse(Candidate {
+                        dist_sq: dist_sq_to_cube(&self.nodes[child_i].bounding_box, posit),
+                        index: child_i,
+                    }));
+                }
+            }
+        }
+
+        nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|c| (c.index, c.dist_sq.sqrt()))
+            .collect()
+    }
+
+    /// Find the `k` nearest neighbors of each target, as with `nearest_neighbors`, returning one result
+    /// per target. This parallelizes over targets.
+    ///
+    /// If `targets_are_sources`, target `i` is treated as body id `i`, and excluded from its own results;
+    /// use this when targets are the bodies used to build the tree.
+    pub fn nearest_neighbors_batch<T>(
+        &self,
+        targets: &[T],
+        targets_are_sources: bool,
+        k: usize,
+    ) -> Vec<Vec<(usize, F)>>
+    where
+        T: BodyModel<F> + Sync,
+    {
+        targets
+            .par_iter()
+            .enumerate()
+            .map(|(i, target)| {
+                let id_target = if targets_are_sources { i } else { usize::MAX };
+                self.nearest_neighbors(target.posit(), id_target, k)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lin_alg::f64::Vec3;
+
+    use super::*;
+    use crate::{BhConfig, test_utils};
+
+    /// Random bodies, and bodies on an integer lattice, where many distances tie exactly. Some lattice bodies
+    /// are duplicated.
+    fn bodies() -> Vec<test_utils::Body> {
+        let mut result = test_utils::bodies(300);
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    let mut body = result[0].clone();
+                    body.posit = Vec3::new(i as f64, j as f64, k as f64) - Vec3::new(1., 1., 1.);
+                    result.push(body);
+                }
+            }
+        }
+        result.extend_from_within(300..310);
+        result
+    }
+
+    fn tree(bodies: &[test_utils::Body]) -> Tree {
+        let config = BhConfig {
+            max_bodies_per_node: 3,
+            ..Default::default()
+        };
+        Tree::new(
+            bodies,
+            &Cube::from_bodies(bodies, 0.1, false).unwrap(),
+            &config,
+        )
+    }
+
+    /// Query points: Random ones, and lattice points.
+    fn query_points() -> Vec<Vec3> {
+        let mut result: Vec<Vec3> = test_utils::bodies(20).iter().map(|b| b.posit).collect();
+        result.extend([
+            Vec3::new(0., 0., 0.),
+            Vec3::new(1., 1., -1.),
+            Vec3::new(0.5, 0., 1.),
+        ]);
+        result
+    }
+
+    fn sorted(mut ids: Vec<usize>) -> Vec<usize> {
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn within_radius_matches_brute_force() {
+        let bodies = bodies();
+        let tree = tree(&bodies);
+
+        for posit in query_points() {
+            // 1 and 2 are lattice distances, so lattice bodies lie exactly on the boundary.
+            for radius in [0., 0.3, 1., 2., 10.] {
+                let expected: Vec<usize> = (0..bodies.len())
+                    .filter(|&i| (bodies[i].posit - posit).magnitude_squared() <= radius * radius)
+                    .collect();
+
+                assert_eq!(
+                    sorted(tree.within_radius(posit, radius)),
+                    expected,
+                    "posit: {posit:?}, radius: {radius}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn within_box_matches_brute_force() {
+        let bodies = bodies();
+        let tree = tree(&bodies);
+
+        // Lattice bodies lie exactly on the faces of some of these.
+        let boxes = [
+            (Vec3::new(-1., -1., -1.), Vec3::new(0., 0., 0.)),
+            (Vec3::new(-0.3, -0.7, 0.), Vec3::new(0.6, 0.2, 2.)),
+            (Vec3::new(0., 0., 0.), Vec3::new(0., 0., 0.)),
+            (Vec3::new(-10., -10., -10.), Vec3::new(10., 10., 10.)),
+            (Vec3::new(0.5, 0.5, 0.5), Vec3::new(-0.5, -0.5, -0.5)),
+        ];
+
+        for (min, max) in boxes {
+            let expected: Vec<usize> = (0..bodies.len())
+                .filter(|&i| {
+                    let p = bodies[i].posit;
+                    (min.x..=max.x).contains(&p.x)
+                        && (min.y..=max.y).contains(&p.y)
+                        && (min.z..=max.z).contains(&p.z)
+                })
+                .collect();
+
+            assert_eq!(
+                sorted(tree.within_box(min, max)),
+                expected,
+                "min: {min:?}, max: {max:?}"
+            );
+        }
+    }
+
+    /// The `k` nearest bodies, excluding `id_target`, sorted by distance, then id.
+    fn nearest_brute_force(
+        bodies: &[test_utils::Body],
+        posit: Vec3,
+        id_target: usize,
+        k: usize,
+    ) -> Vec<(usize, f64)> {
+        let mut all: Vec<(f64, usize)> = (0..bodies.len())
+            .filter(|&i| i != id_target)
+            .map(|i| ((bodies[i].posit - posit).magnitude_squared(), i))
+            .collect();
+        all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        all.into_iter()
+            .take(k)
+            .map(|(dist_sq, i)| (i, dist_sq.sqrt()))
+            .collect()
+    }
+
+    #[test]
+    fn nearest_neighbors_matches_brute_force() {
+        let bodies = bodies();
+        let tree = tree(&bodies);
+
+        for posit in query_points() {
+            // Lattice points have 6 neighbors at distance 1, so some of these cut through ties.
+            for k in [0, 1, 3, 7, 30, bodies.len() + 5] {
+                assert_eq!(
+                    tree.nearest_neighbors(posit, usize::MAX, k),
+                    nearest_brute_force(&bodies, posit, usize::MAX, k),
+                    "posit: {posit:?}, k: {k}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_neighbors_excludes_target() {
+        let bodies = bodies();
+        let tree = tree(&bodies);
+
+        // Includes lattice bodies, and their duplicates, which tie with each other at distance 0.
+        let k = 8;
+        let batch = tree.nearest_neighbors_batch(&bodies, true, k);
+
+        for (i, result) in batch.iter().enumerate() {
+            assert!(result.iter().all(|&(id, _)| id != i), "Body {i}");
+            assert_eq!(*result, nearest_brute_force(&bodies, bodies[i].posit, i, k));
+            assert_eq!(*result, tree.nearest_neighbors(bodies[i].posit, i, k));
+        }
+
+        // A duplicated lattice body finds its copy at distance 0, but not itself.
+        let (id, id_copy) = (300, bodies.len() - 10);
+        assert_eq!(
+            tree.nearest_neighbors(bodies[id].posit, id, 1),
+            vec![(id_copy, 0.)]
+        );
+        assert_eq!(
+            tree.nearest_neighbors(bodies[id].posit, usize::MAX, 2),
+            vec![(id, 0.), (id_copy, 0.)]
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! Updates an existing tree after bodies move, without rebuilding it from scratch. This is a linear
+//! pass, if bodies stay within their leaves' bounding boxes. Use with a padded `Cube`, and rebuild
+//! the tree at a coarser interval. (Quadrupole and octupole moments, if enabled, are recomputed from
+//! each node's bodies.)
+
+use crate::{BhConfig, BodyModel, Float, Tree, aggregate, build_subtree, multipole};
+
+#[derive(Clone, Debug, Default)]
+/// Describes what `Tree::refit` did.
+pub struct RefitReport {
+    /// The number of subtrees rebuilt, due to bodies leaving their leaf's bounding box.
+    pub subtrees_rebuilt: usize,
+    /// Ids of bodies that left the root bounding box. These remain in their previous leaves. If this
+    /// isn't empty, rebuild the tree with a new `Cube`.
+    pub escaped: Vec<usize>,
+}
+
+impl<F: Float> Tree<F> {
+    /// Update the tree for new body positions and masses, keeping its topology. `bodies` must be the same
+    /// bodies, in the same order, as used to build the tree.
+    ///
+    /// We recompute mass and center-of-mass bottom-up. If bodies have left their leaf's bounding box,
+    /// we rebuild the smallest subtree whose bounding box still contains them.
+    pub fn refit<T: BodyModel<F>>(&mut self, bodies: &[T], config: &BhConfig<F>) -> RefitReport {
+        assert_eq!(
+            bodies.len(),
+            self.bodies.len(),
+            "Refit requires the bodies used to build the tree."
+        );
+
+        let mut result = RefitReport::default();
+
+        for (body, &id) in self.bodies.iter_mut().zip(&self.body_ids) {
+            body.posit = bodies[id].posit();
+            body.mass = bodies[id].mass();
+        }
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let (parents, depths) = self.parents_and_depths();
+
+        // Find the smallest ancestor containing each body that left its leaf.
+        let mut to_rebuild = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if !node.children.is_empty() {
+                continue;
+            }
+
+            for body_i in node.body_range.clone() {
+                let posit = self.bodies[body_i].posit;
+                if node.bounding_box.contains(posit) {
+                    continue;
+                }
+
+                let mut ancestor = parents[i];
+                loop {
+                    match ancestor {
+                        Some(a) if self.nodes[a].bounding_box.contains(posit) => {
+                            to_rebuild.push(a);
+                            break;
+                        }
+                        Some(a) => ancestor = parents[a],
+                        None => {
+                            result.escaped.push(self.body_ids[body_i]);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Only rebuild the outermost subtrees; these include any nested ones.
+        to_rebuild.sort_unstable();
+        to_rebuild.dedup();
+        let is_nested = |node_i: usize| {
+            let mut ancestor = parents[node_i];
+            while let Some(a) = ancestor {
+                if to_rebuild.binary_search(&a).is_ok() {
+                    return true;
+                }
+                ancestor = parents[a];
+            }
+            false
+        };
+        let roots: Vec<usize> = to_rebuild
+            .iter()
+            .copied()
+            .filter(|&i| !is_nested(i))
+            .collect();
+
+        // Nodes are in pre-order, so each subtree is contiguous. Rebuild from the end, so indices of
+        // subtrees not yet rebuilt are unaffected.
+        for &root_i in roots.iter().rev() {
+            self.rebuild_subtree(root_i, depths[root_i], config);
+        }
+        result.subtrees_rebuilt = roots.len();
+
+        self.refit_aggregates(config);
+
+        result
+    }
+
+    /// Parent index, and depth of each node.
+    pub(crate) fn parents_and_depths(&self) -> (Vec<Option<usize>>, Vec<usize>) {
+        let mut parents = vec![None; self.nodes.len()];
+        let mut depths = vec![0; self.nodes.len()];
+
+        // Parents always precede their children.
+        for (i, node) in self.nodes.iter().enumerate() {
+            for &child_i in &node.children {
+                parents[child_i] = Some(i);
+                depths[child_i] = depths[i] + 1;
+            }
+        }
+
+        (parents, depths)
+    }
+
+    /// Replace a subtree with a newly-built one over the same bodies, and bounding box.
+    fn rebuild_subtree(&mut self, root_i: usize, depth: usize, config: &BhConfig<F>) {
+        let mut size_old = 0;
+        let mut stack = vec![root_i];
+        while let Some(i) = stack.pop() {
+            size_old += 1;
+            stack.extend(&self.nodes[i].children);
+        }
+        let end_old = root_i + size_old;
+
+        let range = self.nodes[root_i].body_range.clone();
+        let bb = self.nodes[root_i].bounding_box.clone();
+
+        let (mut nodes_new, _) = build_subtree(
+            &mut self.bodies[range.clone()],
+            &mut self.body_ids[range.clone()],
+            range.start,
+            &bb,
+            depth,
+            config,
+        );
+        let size_new = nodes_new.len();
+
+        for i in range {
+            self.body_indices[self.body_ids[i]] = i;
+        }
+
+        for node in &mut nodes_new {
+            node.id += root_i;
+            for child in &mut node.children {
+                *child += root_i;
+            }
+        }
+
+        // Shift references to nodes after the subtree.
+        for node in &mut self.nodes {
+            for child in &mut node.children {
+                if *child >= end_old {
+                    *child = *child - size_old + size_new;
+                }
+            }
+        }
+
+        self.nodes.splice(root_i..end_old, nodes_new);
+
+        for (i, node) in self.nodes.iter_mut().enumerate().skip(root_i + size_new) {
+            node.id = i;
+        }
+    }
+
+    /// Recompute mass and charge aggregates, dipoles, and multipole moments for all nodes, bottom-up; leaves use
+    /// their bodies, and other nodes combine their children. Also updates `depth_limited`.
+    fn refit_aggregates(&mut self, config: &BhConfig<F>) {
+        // Children always follow their parents.
+        for i in (0..self.nodes.len()).rev() {
+            let agg = if self.nodes[i].children.is_empty() {
+                let bodies = &self.bodies[self.nodes[i].body_range.clone()];
+                aggregate(bodies.iter().map(|b| (b.mass, b.posit)))
+            } else {
+                aggregate(
+                    self.nodes[i]
+                        .children
+                        .iter()
+                        .flat_map(|&child_i| self.nodes[child_i].monopoles()),
+                )
+            };
+
+            let center = self.nodes[i].bounding_box.center;
+            let bodies = &self.bodies[self.nodes[i].body_range.clone()];
+
+            let dipole = if self.nodes[i].children.is_empty() {
+                multipole::dipole(bodies, center)
+            } else {
+                multipole::dipole_from_children(
+                    self.nodes[i].children.iter().map(|&c| &self.nodes[c]),
+                    center,
+                )
+            };
+            let (quadrupole, octupole) =
+                multipole::moments(bodies, agg.center_of_mass, config.expansion_order);
+
+            let node = &mut self.nodes[i];
+            node.mass = agg.mass;
+            node.center_of_mass = agg.center_of_mass;
+            node.mass_pos = agg.mass_pos;
+            node.center_pos = agg.center_pos;
+            node.mass_neg = agg.mass_neg;
+            node.center_neg = agg.center_neg;
+            node.dipole = dipole;
+            node.quadrupole = quadrupole;
+            node.octupole = octupole;
+        }
+
+        let (_, depths) = self.parents_and_depths();
+        self.depth_limited = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, n)| {
+                n.children.is_empty()
+                    && n.body_range.len() > config.max_bodies_per_node
+                    && depths[*i] >= config.max_tree_depth
+            })
+            .map(|(i, _)| i)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cube, run_bh, test_utils};
+
+    #[test]
+    fn refit_after_bodies_cross_leaves() {
+        let config = BhConfig {
+            max_bodies_per_node: 2,
+            ..Default::default()
+        };
+
+        let mut bodies = test_utils::bodies(300);
+        let bb = Cube::from_bodies(&bodies, 0.5, false).unwrap();
+        let mut tree = Tree::new(&bodies, &bb, &config);
+
+        for body in &mut bodies {
+            body.posit += body.velocity * 3.;
+        }
+
+        let report = tree.refit(&bodies, &config);
+        assert!(report.subtrees_rebuilt > 0);
+        assert!(report.escaped.is_empty());
+
+        for (i, node) in tree.nodes.iter().enumerate() {
+            assert_eq!(node.id, i);
+
+            // Each node contains its bodies, so each body is inside all of its ancestors.
+            for body in tree.node_bodies(node) {
+                assert!(node.bounding_box.contains(body.posit), "Node {i}");
+            }
+
+            // Children partition their parent's bodies.
+            let child_bodies: usize = node
+                .children
+                .iter()
+                .map(|&c| tree.nodes[c].body_range.len())
+                .sum();
+            if !node.children.is_empty() {
+                assert_eq!(child_bodies, node.body_range.len(), "Node {i}");
+            }
+        }
+
+        for (i, &id) in tree.body_ids.iter().enumerate() {
+            assert_eq!(tree.body_indices[id], i);
+        }
+        assert_eq!(tree.body_indices.len(), tree.body_ids.len());
+
+        let fresh = Tree::new(&bodies, &bb, &config);
+        let force_fn = |acc_dir, mass_src, dist: f64| acc_dir * (mass_src / dist.powi(2));
+
+        for (i, body) in bodies.iter().enumerate() {
+            let refit = run_bh(body.posit, i, &tree, &config, &force_fn);
+            let expected = run_bh(body.posit, i, &fresh, &config, &force_fn);
+            assert!(
+                (refit - expected).magnitude() < 1e-12 * expected.magnitude(),
+                "Body {i}: refit: {refit:?}, fresh: {expected:?}"
+            );
+        }
+    }
+}
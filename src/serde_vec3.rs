@@ -0,0 +1,23 @@
+//! Serde support for `lin_alg`'s `Vec3` types, which don't implement serde's traits. Used with
+//! `serialize_with`, and `deserialize_with`; Vectors are represented as `[x, y, z]`.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Float, Vector};
+
+pub(crate) fn serialize<F, S>(v: &F::Vec3, serializer: S) -> Result<S::Ok, S::Error>
+where
+    F: Float + Serialize,
+    S: Serializer,
+{
+    [v.x(), v.y(), v.z()].serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, F, D>(deserializer: D) -> Result<F::Vec3, D::Error>
+where
+    F: Float + Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let [x, y, z] = <[F; 3]>::deserialize(deserializer)?;
+    Ok(F::Vec3::new(x, y, z))
+}
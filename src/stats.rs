@@ -0,0 +1,116 @@
+//! Statistics on a tree's structure, and on traversals for a given target. Use these to tune
+//! `max_bodies_per_node`, `max_tree_depth`, and θ.
+
+use std::mem::size_of;
+
+use crate::{BhConfig, Float, Node, SourceBody, Tree};
+
+#[derive(Clone, Debug, Default)]
+/// Describes the structure of a tree. See `Tree::stats`.
+pub struct TreeStats {
+    pub num_nodes: usize,
+    pub num_leaves: usize,
+    pub num_bodies: usize,
+    /// The number of nodes at each depth. The root is at depth 0.
+    pub depth_histogram: Vec<usize>,
+    /// The number of leaves containing each number of bodies; index is body count.
+    pub bodies_per_leaf: Vec<usize>,
+    /// Approximate heap and inline memory used by the tree, in bytes.
+    pub memory_bytes: usize,
+    /// If any nodes reached `BhConfig::max_tree_depth` with more than `max_bodies_per_node` bodies. See
+    /// `Tree::depth_limited`.
+    pub depth_limit_reached: bool,
+}
+
+impl TreeStats {
+    /// The deepest level of the tree.
+    pub fn max_depth(&self) -> usize {
+        self.depth_histogram.len().saturating_sub(1)
+    }
+
+    /// The mean number of bodies per leaf.
+    pub fn mean_bodies_per_leaf(&self) -> f64 {
+        if self.num_leaves == 0 {
+            return 0.;
+        }
+        self.num_bodies as f64 / self.num_leaves as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// The work done to compute force on a single target. See `Tree::interaction_count`.
+pub struct InteractionCount {
+    /// Evaluations of grouped nodes' aggregate properties. Mixed-sign nodes count twice, since their
+    /// positive and negative monopoles are evaluated separately.
+    pub nodes: usize,
+    /// Bodies summed individually, from leaves, or from grouped nodes containing the target.
+    pub bodies: usize,
+}
+
+impl<F: Float> Tree<F> {
+    /// Compute statistics on the tree's structure.
+    pub fn stats(&self) -> TreeStats {
+        let (_, depths) = self.parents_and_depths();
+
+        let mut result = TreeStats {
+            num_nodes: self.nodes.len(),
+            num_bodies: self.bodies.len(),
+            depth_limit_reached: !self.depth_limited.is_empty(),
+            ..Default::default()
+        };
+
+        for (node, &depth) in self.nodes.iter().zip(&depths) {
+            if result.depth_histogram.len() <= depth {
+                result.depth_histogram.resize(depth + 1, 0);
+            }
+            result.depth_histogram[depth] += 1;
+
+            if node.children.is_empty() {
+                result.num_leaves += 1;
+
+                let count = node.body_range.len();
+                if result.bodies_per_leaf.len() <= count {
+                    result.bodies_per_leaf.resize(count + 1, 0);
+                }
+                result.bodies_per_leaf[count] += 1;
+            }
+        }
+
+        let children_bytes: usize = self
+            .nodes
+            .iter()
+            .map(|n| n.children.capacity() * size_of::<usize>())
+            .sum();
+
+        result.memory_bytes = size_of::<Self>()
+            + self.nodes.capacity() * size_of::<Node<F>>()
+            + children_bytes
+            + self.bodies.capacity() * size_of::<SourceBody<F>>()
+            + self.body_ids.capacity() * size_of::<usize>()
+            + self.body_indices.capacity() * size_of::<usize>()
+            + self.depth_limited.capacity() * size_of::<usize>();
+
+        result
+    }
+
+    /// The number of node, and body interactions used to compute force on a target, as in `run_bh`.
+    /// `id_target` excludes the target itself, as for `run_bh`.
+    pub fn interaction_count(
+        &self,
+        posit_target: F::Vec3,
+        id_target: usize,
+        config: &BhConfig<F>,
+    ) -> InteractionCount {
+        let mut result = InteractionCount::default();
+
+        for leaf in self.leaves(posit_target, config) {
+            if self.sum_directly(leaf, id_target) {
+                result.bodies += self.bodies_excluding(leaf, id_target).count();
+            } else {
+                result.nodes += leaf.monopoles().count();
+            }
+        }
+
+        result
+    }
+}
@@ -0,0 +1,159 @@
+//! Fixtures shared by tests.
+
+// Some are only used with the `encode` or `serde` features.
+#![allow(dead_code)]
+
+use lin_alg::f64::Vec3;
+
+use crate::{BhConfig, BodyModel, Cube, DynamicBody, ExpansionOrder, Node, OpeningCriterion, Tree};
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "encode", derive(bincode::Encode, bincode::Decode))]
+pub(crate) struct Body {
+    pub posit: Vec3,
+    pub velocity: Vec3,
+    pub mass: f64,
+}
+
+impl BodyModel for Body {
+    fn posit(&self) -> Vec3 {
+        self.posit
+    }
+
+    fn mass(&self) -> f64 {
+        self.mass
+    }
+}
+
+impl DynamicBody for Body {
+    fn velocity(&self) -> Vec3 {
+        self.velocity
+    }
+
+    fn set_posit(&mut self, posit: Vec3) {
+        self.posit = posit;
+    }
+
+    fn set_velocity(&mut self, velocity: Vec3) {
+        self.velocity = velocity;
+    }
+}
+
+/// A config with no default values.
+pub(crate) fn config() -> BhConfig {
+    BhConfig {
+        θ: 0.3,
+        max_bodies_per_node: 2,
+        max_tree_depth: 4,
+        expansion_order: ExpansionOrder::Octupole,
+        periodic: true,
+        opening_criterion: OpeningCriterion::RelativeForce,
+        θ_fallback: 0.7,
+    }
+}
+
+/// Uniform in [0, 1), from a fixed-seed linear congruential generator, so tests are deterministic.
+pub(crate) fn rand_seq(seed: u64) -> impl FnMut() -> f64 {
+    let mut state = seed;
+    move || {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (state >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+/// Bodies uniformly distributed in the cube from -1 to 1. Every third body has negative mass.
+pub(crate) fn bodies(n: usize) -> Vec<Body> {
+    let mut rand = rand_seq(n as u64);
+    let mut rand_vec = move || Vec3::new(rand(), rand(), rand()) * 2. - Vec3::new(1., 1., 1.);
+
+    (0..n)
+        .map(|i| Body {
+            posit: rand_vec(),
+            velocity: rand_vec() * 0.1,
+            mass: if i.is_multiple_of(3) {
+                -0.5
+            } else {
+                1. + (i % 7) as f64 / 7.
+            },
+        })
+        .collect()
+}
+
+/// A tree with mixed signs, and a depth-limited leaf, built with `config`.
+pub(crate) fn tree() -> Tree {
+    let mut bodies = bodies(40);
+    // Coincident bodies can't be separated, so we reach the depth limit.
+    for _ in 0..3 {
+        bodies.push(Body {
+            posit: Vec3::new(0.1, 0.2, 0.3),
+            velocity: Vec3::new(0., 0., 0.),
+            mass: 2.,
+        });
+    }
+
+    let bb = Cube::from_bodies(&bodies, 0.1, false).unwrap();
+    let tree = Tree::new(&bodies, &bb, &config());
+    assert!(!tree.depth_limited.is_empty());
+
+    tree
+}
+
+/// A vector's components as raw bits, for exact comparisons. (`-0.` and `0.` differ, and `NaN`s compare
+/// equal to themselves.)
+pub(crate) fn bits(v: Vec3) -> [u64; 3] {
+    [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]
+}
+
+/// Assert two trees are identical, comparing floats bit-for-bit.
+pub(crate) fn assert_trees_eq(a: &Tree, b: &Tree) {
+    assert_eq!(a.nodes.len(), b.nodes.len());
+    for (x, y) in a.nodes.iter().zip(&b.nodes) {
+        assert_eq!(x.id, y.id);
+        assert_eq!(x.children, y.children);
+        assert_eq!(x.body_range, y.body_range);
+
+        let vecs = |n: &Node| {
+            [
+                n.bounding_box.center,
+                n.center_of_mass,
+                n.center_pos,
+                n.center_neg,
+                n.dipole,
+            ]
+            .map(bits)
+        };
+        let scalars = |n: &Node| {
+            [n.bounding_box.width, n.mass, n.mass_pos, n.mass_neg]
+                .into_iter()
+                .chain(n.quadrupole)
+                .chain(n.octupole)
+                .map(f64::to_bits)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vecs(x), vecs(y), "Node {}", x.id);
+        assert_eq!(scalars(x), scalars(y), "Node {}", x.id);
+    }
+
+    assert_eq!(a.bodies.len(), b.bodies.len());
+    for (x, y) in a.bodies.iter().zip(&b.bodies) {
+        assert_eq!(bits(x.posit), bits(y.posit));
+        assert_eq!(x.mass.to_bits(), y.mass.to_bits());
+    }
+
+    assert_eq!(a.body_ids, b.body_ids);
+    assert_eq!(a.body_indices, b.body_indices);
+    assert_eq!(a.depth_limited, b.depth_limited);
+}
+
+/// Assert two configs are identical, comparing floats bit-for-bit.
+pub(crate) fn assert_configs_eq(a: &BhConfig, b: &BhConfig) {
+    assert_eq!(a.θ.to_bits(), b.θ.to_bits());
+    assert_eq!(a.max_bodies_per_node, b.max_bodies_per_node);
+    assert_eq!(a.max_tree_depth, b.max_tree_depth);
+    assert_eq!(a.expansion_order, b.expansion_order);
+    assert_eq!(a.periodic, b.periodic);
+    assert_eq!(a.opening_criterion, b.opening_criterion);
+    assert_eq!(a.θ_fallback.to_bits(), b.θ_fallback.to_bits());
+}
@@ -0,0 +1,156 @@
+//! Export the tree's nodes, and its bodies, in the legacy ASCII VTK format, for viewing in ParaView
+//! or similar.
+
+use std::{
+    io,
+    io::{BufWriter, Write},
+    mem::size_of,
+};
+
+use crate::{Float, Tree, Vector};
+
+/// VTK cell type ids.
+const VTK_VERTEX: u8 = 1;
+const VTK_HEXAHEDRON: u8 = 12;
+
+/// The VTK name for our float type.
+fn vtk_type<F: Float>() -> &'static str {
+    if size_of::<F>() == 4 {
+        "float"
+    } else {
+        "double"
+    }
+}
+
+fn write_header<W: Write>(w: &mut W, title: &str) -> io::Result<()> {
+    writeln!(w, "# vtk DataFile Version 3.0")?;
+    writeln!(w, "{title}")?;
+    writeln!(w, "ASCII")?;
+    writeln!(w, "DATASET UNSTRUCTURED_GRID")
+}
+
+fn write_scalars<W: Write, T: std::fmt::Display>(
+    w: &mut W,
+    name: &str,
+    type_name: &str,
+    values: impl Iterator<Item = T>,
+) -> io::Result<()> {
+    writeln!(w, "SCALARS {name} {type_name} 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    for v in values {
+        writeln!(w, "{v}")?;
+    }
+    Ok(())
+}
+
+impl<F: Float> Tree<F> {
+    /// Write each node's bounding box as a hexahedron cell, in a VTK unstructured grid. Cell data
+    /// are each node's mass, depth, and body count.
+    pub fn write_vtk_nodes<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut w = BufWriter::new(writer);
+        let (_, depths) = self.parents_and_depths();
+        let n = self.nodes.len();
+
+        write_header(&mut w, "barnes_hut tree nodes")?;
+
+        writeln!(w, "POINTS {} {}", n * 8, vtk_type::<F>())?;
+        let half = F::from_f64(0.5);
+        for node in &self.nodes {
+            let c = node.bounding_box.center;
+            let hw = node.bounding_box.width * half;
+
+            // VTK's hexahedron point order: Counter-clockwise around the bottom face, then the top.
+            for (dx, dy, dz) in [
+                (-hw, -hw, -hw),
+                (hw, -hw, -hw),
+                (hw, hw, -hw),
+                (-hw, hw, -hw),
+                (-hw, -hw, hw),
+                (hw, -hw, hw),
+                (hw, hw, hw),
+                (-hw, hw, hw),
+            ] {
+                writeln!(w, "{} {} {}", c.x() + dx, c.y() + dy, c.z() + dz)?;
+            }
+        }
+
+        writeln!(w, "CELLS {n} {}", n * 9)?;
+        for i in 0..n {
+            let p = i * 8;
+            writeln!(
+                w,
+                "8 {} {} {} {} {} {} {} {}",
+                p,
+                p + 1,
+                p + 2,
+                p + 3,
+                p + 4,
+                p + 5,
+                p + 6,
+                p + 7
+            )?;
+        }
+
+        writeln!(w, "CELL_TYPES {n}")?;
+        for _ in 0..n {
+            writeln!(w, "{VTK_HEXAHEDRON}")?;
+        }
+
+        writeln!(w, "CELL_DATA {n}")?;
+        write_scalars(
+            &mut w,
+            "mass",
+            vtk_type::<F>(),
+            self.nodes.iter().map(|n| n.mass),
+        )?;
+        write_scalars(&mut w, "depth", "int", depths.iter())?;
+        write_scalars(
+            &mut w,
+            "body_count",
+            "int",
+            self.nodes.iter().map(|n| n.body_range.len()),
+        )?;
+
+        w.flush()
+    }
+
+    /// Write bodies as points, in a VTK unstructured grid. Point data are each body's mass, and id.
+    pub fn write_vtk_bodies<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut w = BufWriter::new(writer);
+        let n = self.bodies.len();
+
+        write_header(&mut w, "barnes_hut bodies")?;
+
+        writeln!(w, "POINTS {n} {}", vtk_type::<F>())?;
+        for body in &self.bodies {
+            writeln!(
+                w,
+                "{} {} {}",
+                body.posit.x(),
+                body.posit.y(),
+                body.posit.z()
+            )?;
+        }
+
+        writeln!(w, "CELLS {n} {}", n * 2)?;
+        for i in 0..n {
+            writeln!(w, "1 {i}")?;
+        }
+
+        writeln!(w, "CELL_TYPES {n}")?;
+        for _ in 0..n {
+            writeln!(w, "{VTK_VERTEX}")?;
+        }
+
+        writeln!(w, "POINT_DATA {n}")?;
+        write_scalars(
+            &mut w,
+            "mass",
+            vtk_type::<F>(),
+            self.bodies.iter().map(|b| b.mass),
+        )?;
+        write_scalars(&mut w, "id", "int", self.body_ids.iter())?;
+
+        w.flush()
+    }
+}